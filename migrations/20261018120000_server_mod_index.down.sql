@@ -0,0 +1 @@
+DROP INDEX IF EXISTS server_mod_mod_id;
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS server_mod_mod_id ON server_mod (mod_id, time);
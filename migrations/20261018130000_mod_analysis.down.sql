@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS modpack_mod;
+DROP TABLE IF EXISTS modpack;
+DROP TABLE IF EXISTS mod_pair;
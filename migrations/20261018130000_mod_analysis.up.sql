@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS mod_pair (
+    mod_a                INTEGER NOT NULL,
+    mod_b                INTEGER NOT NULL,
+    lobbies              INTEGER NOT NULL,
+    PRIMARY KEY (mod_a, mod_b)
+) STRICT;
+
+CREATE INDEX IF NOT EXISTS mod_pair_mod_b ON mod_pair (mod_b);
+
+CREATE TABLE IF NOT EXISTS modpack (
+    modpack_id           INTEGER PRIMARY KEY NOT NULL,
+    mod_ids              TEXT NOT NULL UNIQUE,
+    name                 TEXT NOT NULL,
+    size                 INTEGER NOT NULL,
+    lobbies              INTEGER NOT NULL,
+    updated              INTEGER NOT NULL
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS modpack_mod (
+    modpack_id           INTEGER NOT NULL,
+    mod_id               INTEGER NOT NULL,
+    PRIMARY KEY (modpack_id, mod_id),
+    FOREIGN KEY (modpack_id) REFERENCES modpack (modpack_id) ON DELETE CASCADE
+) STRICT;
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS mod_category_event;
+ALTER TABLE mod DROP COLUMN current_category_time;
+ALTER TABLE mod DROP COLUMN current_category;
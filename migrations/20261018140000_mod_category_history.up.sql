@@ -0,0 +1,21 @@
+ALTER TABLE mod ADD COLUMN current_category INTEGER;
+ALTER TABLE mod ADD COLUMN current_category_time INTEGER;
+
+UPDATE mod SET (current_category, current_category_time) = (
+    SELECT category, time
+    FROM server_mod
+    WHERE server_mod.mod_id = mod.mod_id
+    ORDER BY time DESC
+    LIMIT 1
+);
+
+CREATE TABLE IF NOT EXISTS mod_category_event (
+    event_id             INTEGER PRIMARY KEY NOT NULL,
+    mod_id               INTEGER NOT NULL,
+    time                 INTEGER NOT NULL,
+    old_category         INTEGER NOT NULL,
+    new_category         INTEGER NOT NULL,
+    notified             INTEGER NOT NULL DEFAULT 0
+) STRICT;
+
+CREATE INDEX IF NOT EXISTS mod_category_event_mod_id ON mod_category_event (mod_id, time);
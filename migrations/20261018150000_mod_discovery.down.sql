@@ -0,0 +1,3 @@
+ALTER TABLE mod DROP COLUMN announced;
+ALTER TABLE mod DROP COLUMN first_lobby_id;
+ALTER TABLE mod DROP COLUMN first_seen;
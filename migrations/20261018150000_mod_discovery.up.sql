@@ -0,0 +1,15 @@
+ALTER TABLE mod ADD COLUMN first_seen INTEGER;
+ALTER TABLE mod ADD COLUMN first_lobby_id TEXT;
+ALTER TABLE mod ADD COLUMN announced INTEGER NOT NULL DEFAULT 0;
+
+UPDATE mod SET (first_seen, first_lobby_id) = (
+    SELECT time, lobby_id
+    FROM server_mod
+    JOIN server USING(time, lobby_id)
+    WHERE server_mod.mod_id = mod.mod_id AND server.password = 0
+    ORDER BY time
+    LIMIT 1
+);
+
+-- mods seen before this migration are not new
+UPDATE mod SET announced = 1;
@@ -0,0 +1,11 @@
+CREATE TABLE discord_message_old (
+    message_id           TEXT NOT NULL PRIMARY KEY,
+    lobby_id             TEXT NOT NULL UNIQUE,
+    last_updated         INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+) STRICT;
+
+INSERT OR IGNORE INTO discord_message_old (message_id, lobby_id, last_updated)
+SELECT message_id, lobby_id, last_updated FROM discord_message;
+
+DROP TABLE discord_message;
+ALTER TABLE discord_message_old RENAME TO discord_message;
@@ -0,0 +1,13 @@
+CREATE TABLE discord_message_new (
+    target               TEXT NOT NULL,
+    message_id           TEXT NOT NULL PRIMARY KEY,
+    lobby_id             TEXT NOT NULL,
+    last_updated         INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    UNIQUE (target, lobby_id)
+) STRICT;
+
+INSERT INTO discord_message_new (target, message_id, lobby_id, last_updated)
+SELECT 'default', message_id, lobby_id, last_updated FROM discord_message;
+
+DROP TABLE discord_message;
+ALTER TABLE discord_message_new RENAME TO discord_message;
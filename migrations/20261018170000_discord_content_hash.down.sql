@@ -0,0 +1,2 @@
+ALTER TABLE discord_message DROP COLUMN last_edited;
+ALTER TABLE discord_message DROP COLUMN content_hash;
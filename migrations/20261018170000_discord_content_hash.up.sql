@@ -0,0 +1,4 @@
+ALTER TABLE discord_message ADD COLUMN content_hash TEXT;
+ALTER TABLE discord_message ADD COLUMN last_edited INTEGER NOT NULL DEFAULT 0;
+
+UPDATE discord_message SET last_edited = last_updated;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS server_lobby_id;
+
+ALTER TABLE discord_message DROP COLUMN closed_at;
@@ -0,0 +1,3 @@
+ALTER TABLE discord_message ADD COLUMN closed_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS server_lobby_id ON server (lobby_id, time);
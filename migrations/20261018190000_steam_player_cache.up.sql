@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS steam_player (
+    steamid              TEXT NOT NULL PRIMARY KEY,
+    personaname          TEXT NOT NULL,
+    avatar               TEXT NOT NULL,
+    avatarfull           TEXT NOT NULL,
+    updated              INTEGER NOT NULL
+) STRICT;
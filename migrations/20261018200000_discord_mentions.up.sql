@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS discord_mention (
+    target               TEXT NOT NULL,
+    kind                 TEXT NOT NULL,
+    id                   TEXT NOT NULL,
+    last_mentioned       INTEGER NOT NULL,
+    PRIMARY KEY (target, kind, id)
+) STRICT;
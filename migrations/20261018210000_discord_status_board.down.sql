@@ -0,0 +1,20 @@
+DROP INDEX IF EXISTS discord_message_board;
+
+CREATE TABLE discord_message_new (
+    target               TEXT NOT NULL,
+    message_id           TEXT NOT NULL PRIMARY KEY,
+    lobby_id             TEXT NOT NULL,
+    last_updated         INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    content_hash         TEXT,
+    last_edited          INTEGER NOT NULL DEFAULT 0,
+    closed_at            INTEGER,
+    UNIQUE (target, lobby_id)
+) STRICT;
+
+INSERT INTO discord_message_new (target, message_id, lobby_id, last_updated, content_hash, last_edited, closed_at)
+SELECT target, message_id, lobby_id, last_updated, content_hash, last_edited, closed_at
+FROM discord_message
+WHERE kind = 'lobby';
+
+DROP TABLE discord_message;
+ALTER TABLE discord_message_new RENAME TO discord_message;
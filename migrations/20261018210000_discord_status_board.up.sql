@@ -0,0 +1,21 @@
+-- status board messages are not tied to a lobby
+CREATE TABLE discord_message_new (
+    target               TEXT NOT NULL,
+    kind                 TEXT NOT NULL DEFAULT 'lobby',
+    message_id           TEXT NOT NULL PRIMARY KEY,
+    lobby_id             TEXT,
+    last_updated         INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    content_hash         TEXT,
+    last_edited          INTEGER NOT NULL DEFAULT 0,
+    closed_at            INTEGER,
+    UNIQUE (target, lobby_id),
+    CHECK ((kind = 'board') = (lobby_id IS NULL))
+) STRICT;
+
+INSERT INTO discord_message_new (target, message_id, lobby_id, last_updated, content_hash, last_edited, closed_at)
+SELECT target, message_id, lobby_id, last_updated, content_hash, last_edited, closed_at FROM discord_message;
+
+DROP TABLE discord_message;
+ALTER TABLE discord_message_new RENAME TO discord_message;
+
+CREATE UNIQUE INDEX IF NOT EXISTS discord_message_board ON discord_message (target) WHERE kind = 'board';
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS report (
+    name                 TEXT NOT NULL,
+    period_start         INTEGER NOT NULL,
+    period_end           INTEGER NOT NULL,
+    claimed_at           INTEGER NOT NULL,
+    message_id           TEXT,
+    PRIMARY KEY (name, period_start)
+) STRICT;
@@ -0,0 +1,2 @@
+ALTER TABLE discord_message DROP COLUMN last_state;
+ALTER TABLE discord_message DROP COLUMN thread_id;
@@ -0,0 +1,2 @@
+ALTER TABLE discord_message ADD COLUMN thread_id TEXT;
+ALTER TABLE discord_message ADD COLUMN last_state TEXT;
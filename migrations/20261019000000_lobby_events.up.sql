@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS event (
+    event_id             INTEGER PRIMARY KEY NOT NULL,
+    time                 INTEGER NOT NULL,
+    lobby_id             TEXT NOT NULL,
+    kind                 TEXT NOT NULL CHECK (kind IN (
+        'lobby_opened',
+        'lobby_closed',
+        'mission_started',
+        'returned_to_rig',
+        'player_count_changed',
+        'mods_changed',
+        'renamed',
+        'became_full'
+    )),
+    data                 TEXT NOT NULL
+) STRICT;
+
+CREATE INDEX IF NOT EXISTS event_lobby_id ON event (lobby_id, event_id);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS webhook_subscriber (
+    name                 TEXT PRIMARY KEY NOT NULL,
+    last_event_id        INTEGER NOT NULL
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS webhook_delivery (
+    delivery_id          INTEGER PRIMARY KEY NOT NULL,
+    subscriber           TEXT NOT NULL,
+    event_id             INTEGER NOT NULL REFERENCES event (event_id),
+    attempts             INTEGER NOT NULL DEFAULT 0,
+    next_attempt         INTEGER NOT NULL,
+    last_status          INTEGER,
+    last_error           TEXT,
+    delivered_at         INTEGER,
+    UNIQUE (subscriber, event_id)
+) STRICT;
+
+CREATE INDEX IF NOT EXISTS webhook_delivery_pending ON webhook_delivery (subscriber, next_attempt)
+    WHERE delivered_at IS NULL;
@@ -0,0 +1,46 @@
+DROP TABLE IF EXISTS lobby_watch;
+
+CREATE TABLE event_old (
+    event_id             INTEGER PRIMARY KEY NOT NULL,
+    time                 INTEGER NOT NULL,
+    lobby_id             TEXT NOT NULL,
+    kind                 TEXT NOT NULL CHECK (kind IN (
+        'lobby_opened',
+        'lobby_closed',
+        'mission_started',
+        'returned_to_rig',
+        'player_count_changed',
+        'mods_changed',
+        'renamed',
+        'became_full'
+    )),
+    data                 TEXT NOT NULL
+) STRICT;
+
+INSERT INTO event_old (event_id, time, lobby_id, kind, data)
+SELECT event_id, time, lobby_id, kind, data FROM event WHERE kind != 'slot_opened';
+
+CREATE TABLE webhook_delivery_old (
+    delivery_id          INTEGER PRIMARY KEY NOT NULL,
+    subscriber           TEXT NOT NULL,
+    event_id             INTEGER NOT NULL REFERENCES event_old (event_id),
+    attempts             INTEGER NOT NULL DEFAULT 0,
+    next_attempt         INTEGER NOT NULL,
+    last_status          INTEGER,
+    last_error           TEXT,
+    delivered_at         INTEGER,
+    UNIQUE (subscriber, event_id)
+) STRICT;
+
+INSERT INTO webhook_delivery_old
+SELECT delivery_id, subscriber, event_id, attempts, next_attempt, last_status, last_error, delivered_at
+FROM webhook_delivery
+WHERE event_id IN (SELECT event_id FROM event_old);
+
+DROP TABLE webhook_delivery;
+DROP TABLE event;
+ALTER TABLE event_old RENAME TO event;
+ALTER TABLE webhook_delivery_old RENAME TO webhook_delivery;
+CREATE INDEX IF NOT EXISTS event_lobby_id ON event (lobby_id, event_id);
+CREATE INDEX IF NOT EXISTS webhook_delivery_pending ON webhook_delivery (subscriber, next_attempt)
+    WHERE delivered_at IS NULL;
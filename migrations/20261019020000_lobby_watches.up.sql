@@ -0,0 +1,53 @@
+CREATE TABLE event_new (
+    event_id             INTEGER PRIMARY KEY NOT NULL,
+    time                 INTEGER NOT NULL,
+    lobby_id             TEXT NOT NULL,
+    kind                 TEXT NOT NULL CHECK (kind IN (
+        'lobby_opened',
+        'lobby_closed',
+        'mission_started',
+        'returned_to_rig',
+        'player_count_changed',
+        'mods_changed',
+        'renamed',
+        'became_full',
+        'slot_opened'
+    )),
+    data                 TEXT NOT NULL
+) STRICT;
+
+INSERT INTO event_new (event_id, time, lobby_id, kind, data)
+SELECT event_id, time, lobby_id, kind, data FROM event;
+
+-- deliveries reference the events, so they move along to the new table
+CREATE TABLE webhook_delivery_new (
+    delivery_id          INTEGER PRIMARY KEY NOT NULL,
+    subscriber           TEXT NOT NULL,
+    event_id             INTEGER NOT NULL REFERENCES event_new (event_id),
+    attempts             INTEGER NOT NULL DEFAULT 0,
+    next_attempt         INTEGER NOT NULL,
+    last_status          INTEGER,
+    last_error           TEXT,
+    delivered_at         INTEGER,
+    UNIQUE (subscriber, event_id)
+) STRICT;
+
+INSERT INTO webhook_delivery_new
+SELECT delivery_id, subscriber, event_id, attempts, next_attempt, last_status, last_error, delivered_at
+FROM webhook_delivery;
+
+DROP TABLE webhook_delivery;
+DROP TABLE event;
+ALTER TABLE event_new RENAME TO event;
+ALTER TABLE webhook_delivery_new RENAME TO webhook_delivery;
+CREATE INDEX IF NOT EXISTS event_lobby_id ON event (lobby_id, event_id);
+CREATE INDEX IF NOT EXISTS webhook_delivery_pending ON webhook_delivery (subscriber, next_attempt)
+    WHERE delivered_at IS NULL;
+
+CREATE TABLE IF NOT EXISTS lobby_watch (
+    lobby_id             TEXT NOT NULL,
+    discord_user_id      TEXT NOT NULL,
+    created_at           INTEGER NOT NULL,
+    after_event_id       INTEGER NOT NULL,
+    PRIMARY KEY (lobby_id, discord_user_id)
+) STRICT;
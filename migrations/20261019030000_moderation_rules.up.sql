@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS moderation_rule (
+    rule_id              INTEGER PRIMARY KEY NOT NULL,
+    kind                 TEXT NOT NULL CHECK (kind IN ('host', 'lobby', 'name')),
+    value                TEXT NOT NULL,
+    action               TEXT NOT NULL CHECK (action IN ('block', 'allow')),
+    reason               TEXT NOT NULL DEFAULT '',
+    created_at           INTEGER NOT NULL,
+    expires_at           INTEGER
+) STRICT;
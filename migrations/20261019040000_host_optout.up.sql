@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS host_optout (
+    host_user_id         TEXT PRIMARY KEY NOT NULL,
+    created_at           INTEGER NOT NULL
+) STRICT;
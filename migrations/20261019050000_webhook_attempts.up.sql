@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS webhook_attempt (
+    attempt_id           INTEGER PRIMARY KEY NOT NULL,
+    delivery_id          INTEGER NOT NULL REFERENCES webhook_delivery (delivery_id),
+    time                 INTEGER NOT NULL,
+    status               INTEGER,
+    error                TEXT
+) STRICT;
+
+CREATE INDEX IF NOT EXISTS webhook_attempt_delivery_id ON webhook_attempt (delivery_id);
@@ -0,0 +1 @@
+DROP VIEW IF EXISTS server_modpack;
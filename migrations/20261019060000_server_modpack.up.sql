@@ -0,0 +1,21 @@
+-- Largest modpack fully contained in the mods of each snapshot, if any
+CREATE VIEW IF NOT EXISTS server_modpack AS
+SELECT time,
+    lobby_id,
+    (SELECT name
+        FROM modpack
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM modpack_mod
+            WHERE
+                modpack_mod.modpack_id = modpack.modpack_id
+                AND mod_id NOT IN (
+                    SELECT mod_id
+                    FROM server_mod
+                    WHERE server_mod.time = server.time AND server_mod.lobby_id = server.lobby_id
+                )
+        )
+        ORDER BY size DESC
+        LIMIT 1
+    ) AS modpack
+FROM server;
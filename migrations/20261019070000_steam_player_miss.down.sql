@@ -0,0 +1 @@
+DROP TABLE IF EXISTS steam_player_miss;
@@ -0,0 +1,5 @@
+-- Steam IDs GetPlayerSummaries returned nothing for, so they are not asked for on every poll
+CREATE TABLE IF NOT EXISTS steam_player_miss (
+    steamid              TEXT NOT NULL PRIMARY KEY,
+    checked              INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,228 @@
+use anyhow::Result;
+
+use itertools::Itertools;
+use sqlx::sqlite::SqlitePool;
+use tracing::info;
+
+use std::collections::{BTreeSet, HashMap};
+
+/// How far back lobbies are considered when analysing mod usage
+const WINDOW_SECONDS: i64 = 60 * 60 * 24 * 30;
+/// Pairs seen together in fewer lobbies than this are not stored
+const PAIR_MIN_LOBBIES: usize = 2;
+/// Smallest mod set considered a modpack
+const MODPACK_MIN_MODS: usize = 3;
+/// Number of distinct lobbies a mod set must be seen in to become a modpack
+const MODPACK_MIN_LOBBIES: usize = 3;
+
+#[tracing::instrument(skip(pool))]
+pub async fn update_mod_analysis(pool: &SqlitePool, time: i64) -> Result<()> {
+    let since = time - WINDOW_SECONDS;
+
+    // mod set of the most recent snapshot of every lobby within the window
+    let lobbies: Vec<BTreeSet<i64>> = sqlx::query!(
+        r#"SELECT lobby_id, group_concat(mod_id) AS "mods!: String"
+            FROM server_mod
+            WHERE (time, lobby_id) IN (
+                SELECT MAX(time), lobby_id
+                FROM server
+                WHERE time > ?
+                GROUP BY lobby_id
+            )
+            GROUP BY lobby_id
+        "#,
+        since,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| r.mods.split(',').filter_map(|id| id.parse().ok()).collect())
+    .collect();
+
+    info!("analysing mods of {} lobbies", lobbies.len());
+
+    let (mod_lobbies, pairs) = co_occurrences(&lobbies);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM mod_pair")
+        .execute(&mut *tx)
+        .await?;
+    for ((a, b), count) in pairs {
+        let count = count as i64;
+        sqlx::query!(
+            "INSERT INTO mod_pair (mod_a, mod_b, lobbies) VALUES (?, ?, ?)",
+            a,
+            b,
+            count
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    let names: HashMap<i64, String> = sqlx::query!("SELECT mod_id, name FROM mod")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .filter_map(|r| r.name.map(|name| (r.mod_id, name)))
+        .collect();
+
+    let modpacks = find_modpacks(&lobbies);
+    info!("found {} modpacks", modpacks.len());
+
+    let mut tx = pool.begin().await?;
+    for (mods, count) in modpacks {
+        let mod_ids = mods.iter().join(",");
+        let name = modpack_name(mods, &names, &mod_lobbies);
+        let size = mods.len() as i64;
+        let count = count as i64;
+        let modpack_id = sqlx::query!(
+            r#"INSERT INTO modpack (mod_ids, name, size, lobbies, updated)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(mod_ids) DO UPDATE SET name = excluded.name, lobbies = excluded.lobbies, updated = excluded.updated
+                RETURNING modpack_id
+            "#,
+            mod_ids,
+            name,
+            size,
+            count,
+            time,
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .modpack_id;
+        for mod_id in mods {
+            sqlx::query!(
+                "INSERT OR IGNORE INTO modpack_mod (modpack_id, mod_id) VALUES (?, ?)",
+                modpack_id,
+                mod_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    sqlx::query!("DELETE FROM modpack WHERE updated < ?", time)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Number of lobbies running each mod, and of those running each pair of mods seen together in
+/// at least [`PAIR_MIN_LOBBIES`] lobbies. Pairs are ordered by mod ID.
+fn co_occurrences(lobbies: &[BTreeSet<i64>]) -> (HashMap<i64, usize>, HashMap<(i64, i64), usize>) {
+    let mut mod_lobbies = HashMap::<i64, usize>::new();
+    let mut pairs = HashMap::<(i64, i64), usize>::new();
+    for mods in lobbies {
+        for m in mods {
+            *mod_lobbies.entry(*m).or_default() += 1;
+        }
+        for (a, b) in mods.iter().copied().tuple_combinations() {
+            *pairs.entry((a, b)).or_default() += 1;
+        }
+    }
+    pairs.retain(|_, count| *count >= PAIR_MIN_LOBBIES);
+    (mod_lobbies, pairs)
+}
+
+/// Finds mod sets run as a whole by several lobbies. Candidates are exact sets seen in at least
+/// [`MODPACK_MIN_LOBBIES`] lobbies; a candidate is dropped if a larger candidate containing it is
+/// run by the same number of lobbies, as it is then just an incomplete view of that modpack.
+fn find_modpacks(lobbies: &[BTreeSet<i64>]) -> Vec<(&BTreeSet<i64>, usize)> {
+    let candidates = lobbies
+        .iter()
+        .filter(|mods| mods.len() >= MODPACK_MIN_MODS)
+        .counts()
+        .into_iter()
+        .filter(|(_, count)| *count >= MODPACK_MIN_LOBBIES)
+        .map(|(mods, _)| {
+            let count = lobbies.iter().filter(|l| l.is_superset(mods)).count();
+            (mods, count)
+        })
+        .collect::<Vec<_>>();
+
+    candidates
+        .iter()
+        .filter(|(mods, count)| {
+            !candidates.iter().any(|(other, other_count)| {
+                other.len() > mods.len() && other.is_superset(mods) && other_count == count
+            })
+        })
+        .copied()
+        .collect()
+}
+
+/// Names a modpack after its most distinctive mod, i.e. the one seen in the fewest lobbies.
+fn modpack_name(
+    mods: &BTreeSet<i64>,
+    names: &HashMap<i64, String>,
+    mod_lobbies: &HashMap<i64, usize>,
+) -> String {
+    let defining = mods
+        .iter()
+        .filter_map(|m| names.get(m).map(|name| (m, name)))
+        .min_by_key(|(m, _)| (mod_lobbies.get(m).copied().unwrap_or(0), **m));
+    match defining {
+        Some((_, name)) => format!("{} + {} mods", name, mods.len() - 1),
+        None => format!("Modpack of {} mods", mods.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobbies(sets: &[(&[i64], usize)]) -> Vec<BTreeSet<i64>> {
+        sets.iter()
+            .flat_map(|(mods, n)| std::iter::repeat_n(mods.iter().copied().collect(), *n))
+            .collect()
+    }
+
+    #[test]
+    fn counts_mods_and_pairs() {
+        let lobbies = lobbies(&[(&[1, 2, 3], 2), (&[2, 1], 1), (&[3, 4], 1)]);
+        let (mod_lobbies, pairs) = co_occurrences(&lobbies);
+        assert_eq!(mod_lobbies, HashMap::from([(1, 3), (2, 3), (3, 3), (4, 1)]));
+        // 3 and 4 are seen together only once
+        assert_eq!(
+            pairs,
+            HashMap::from([((1, 2), 3), ((1, 3), 2), ((2, 3), 2)])
+        );
+    }
+
+    #[test]
+    fn modpacks_need_enough_mods_and_lobbies() {
+        let lobbies = lobbies(&[
+            (&[1, 2, 3], 3),
+            // too few lobbies
+            (&[4, 5, 6], 2),
+            // too few mods
+            (&[7, 8], 5),
+        ]);
+        let modpacks = find_modpacks(&lobbies);
+        let expected = BTreeSet::from([1, 2, 3]);
+        assert_eq!(modpacks, [(&expected, 3)]);
+    }
+
+    #[test]
+    fn modpacks_count_lobbies_adding_mods() {
+        let lobbies = lobbies(&[(&[1, 2, 3], 3), (&[1, 2, 3, 4], 1), (&[1, 2, 3, 4, 5], 3)]);
+        let mut modpacks = find_modpacks(&lobbies);
+        modpacks.sort_by_key(|(mods, _)| mods.len());
+        let (small, large) = (BTreeSet::from([1, 2, 3]), BTreeSet::from([1, 2, 3, 4, 5]));
+        assert_eq!(modpacks, [(&small, 7), (&large, 3)]);
+    }
+
+    #[test]
+    fn modpacks_are_named_after_their_rarest_mod() {
+        let mods = BTreeSet::from([1, 2, 3]);
+        let names = HashMap::from([(1, "Common".to_string()), (2, "Rare".to_string())]);
+        let mod_lobbies = HashMap::from([(1, 10), (2, 3), (3, 1)]);
+        assert_eq!(modpack_name(&mods, &names, &mod_lobbies), "Rare + 2 mods");
+        assert_eq!(
+            modpack_name(&mods, &HashMap::new(), &mod_lobbies),
+            "Modpack of 3 mods"
+        );
+    }
+}
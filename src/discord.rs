@@ -1,30 +1,46 @@
 use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqlitePool;
 
-use lazy_static::lazy_static;
-use regex::Regex;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use tracing::warn;
 
-use anyhow::{anyhow, Result};
-use tracing::{info, warn};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Mod {
-    id: i64,
-    category: Option<i32>,
-    name: Option<String>,
-    url: Option<String>,
-}
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::lobby::{category_name, latest_lobbies, latest_snapshot, Class, Lobby, Mod};
+use crate::moderation::Moderation;
+use crate::ratelimit::{self, RateLimiter};
+use crate::settings::{ClosedBehavior, DiscordSettings, DiscordTarget};
+use crate::sink::{self, Delivery, Sink};
+use crate::steam::{get_players, Player};
+
+/// Embed color of lobbies that have closed
+const CLOSED_COLOR: u32 = 0x4f545c;
+/// Messages of lobbies that have not matched for this long are closed or deleted, so lobbies
+/// missing from a single poll keep their message
+const GONE_AFTER_MINUTES: i64 = 10;
+
+// Discord rejects messages exceeding any of these, lengths are in characters
+const TITLE_LIMIT: usize = 256;
+const DESCRIPTION_LIMIT: usize = 4096;
+const FIELD_NAME_LIMIT: usize = 256;
+const FIELD_VALUE_LIMIT: usize = 1024;
+const AUTHOR_NAME_LIMIT: usize = 256;
+const FIELD_COUNT_LIMIT: usize = 25;
+const CONTENT_LIMIT: usize = 2000;
+const EMBED_COUNT_LIMIT: usize = 10;
+/// Combined length of all titles, descriptions, field names and values and author names
+const TOTAL_LIMIT: usize = 6000;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
-enum WebhookResponse {
+pub enum WebhookResponse {
     Success {
         id: String,
-    },
-    Ratelimit {
-        global: bool,
-        message: String,
-        retry_after: f32,
+        channel_id: Option<String>,
     },
     Error {
         message: String,
@@ -35,22 +51,54 @@ enum WebhookResponse {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WebhookBody {
     pub avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
     pub embeds: Vec<WebhookEmbed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_mentions: Option<AllowedMentions>,
+    /// Creates a forum post with this name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_name: Option<String>,
+}
+
+/// Restricts who a message may ping. Nothing in `parse` means only the listed roles and users are
+/// pinged, never `@everyone` or mentions in user supplied text.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AllowedMentions {
+    pub parse: Vec<String>,
+    pub roles: Vec<String>,
+    pub users: Vec<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WebhookEmbed {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub title: String,
-    pub author: WebhookAuthor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<WebhookAuthor>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub description: String,
     pub fields: Vec<WebhookField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<WebhookImage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<u32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookImage {
+    pub url: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WebhookAuthor {
     pub name: String,
-    pub icon_url: String,
-    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -60,53 +108,186 @@ pub struct WebhookField {
     pub inline: bool,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct SteamPlayerRequest {
-    pub response: SteamPlayerResponse,
+impl WebhookBody {
+    /// Shortens the message until Discord accepts it. Overlong text is cut off with an ellipsis,
+    /// embeds with too many fields are split into several embeds and trailing fields that do not
+    /// fit are replaced with an "...and N more" field.
+    pub fn fit_limits(mut self) -> Self {
+        let mut embeds = vec![];
+        for embed in self.embeds {
+            embeds.extend(split_fields(truncate_embed(embed)));
+        }
+        let mut dropped: usize = embeds
+            .iter()
+            .skip(EMBED_COUNT_LIMIT)
+            .map(|e| e.fields.len())
+            .sum();
+        embeds.truncate(EMBED_COUNT_LIMIT);
+
+        loop {
+            let note = (dropped > 0).then(|| WebhookField {
+                name: "\u{200B}".to_string(),
+                value: format!("...and {} more", dropped),
+                inline: false,
+            });
+            let size = embeds.iter().map(embed_chars).sum::<usize>()
+                + note.as_ref().map_or(0, field_chars);
+            let room = note.is_none()
+                || embeds
+                    .last()
+                    .is_some_and(|e| e.fields.len() < FIELD_COUNT_LIMIT);
+            if size <= TOTAL_LIMIT && room {
+                if let (Some(note), Some(embed)) = (note, embeds.last_mut()) {
+                    embed.fields.push(note);
+                }
+                break;
+            }
+
+            if let Some(embed) = embeds.iter_mut().rev().find(|e| !e.fields.is_empty()) {
+                embed.fields.pop();
+                dropped += 1;
+                // continuation embeds left without fields have nothing to show
+                if embeds.len() > 1 && embeds.last().is_some_and(is_empty_embed) {
+                    embeds.pop();
+                }
+            } else if let Some(embed) = embeds.iter_mut().max_by_key(|e| e.description.len()) {
+                let over = size.saturating_sub(TOTAL_LIMIT).max(1);
+                let len = embed.description.chars().count();
+                if len == 0 {
+                    break;
+                }
+                embed.description = truncate_chars(&embed.description, len.saturating_sub(over));
+            } else {
+                break;
+            }
+        }
+
+        self.embeds = embeds;
+        self
+    }
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct SteamPlayerResponse {
-    pub players: Vec<SteamPlayer>,
+/// Cuts `text` off with an ellipsis if it is longer than `limit` characters
+pub fn truncate_chars(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(limit.saturating_sub(1)).collect();
+    if limit > 0 {
+        truncated.push('…');
+    }
+    truncated
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct SteamPlayer {
-    pub steamid: String,
-    pub communityvisibilitystate: i64,
-    pub profilestate: Option<i64>,
-    pub personaname: String,
-    pub profileurl: String,
-    pub avatar: String,
-    pub avatarmedium: String,
-    pub avatarfull: String,
-    pub avatarhash: String,
-    pub personastate: i64,
-    pub primaryclanid: Option<String>,
-    pub timecreated: Option<i64>,
-    pub personastateflags: Option<i64>,
+fn truncate_embed(mut embed: WebhookEmbed) -> WebhookEmbed {
+    embed.title = truncate_chars(&embed.title, TITLE_LIMIT);
+    embed.description = truncate_chars(&embed.description, DESCRIPTION_LIMIT);
+    if let Some(author) = &mut embed.author {
+        author.name = truncate_chars(&author.name, AUTHOR_NAME_LIMIT);
+    }
+    for field in &mut embed.fields {
+        field.name = truncate_chars(&field.name, FIELD_NAME_LIMIT);
+        field.value = truncate_chars(&field.value, FIELD_VALUE_LIMIT);
+    }
+    embed
 }
 
-fn format_mod_field(mods: &[Mod], category: i32, name: &str) -> Option<WebhookField> {
-    let mut value = String::with_capacity(1000);
-    let filtered_mods: Vec<&Mod> = mods
+/// Moves fields beyond the per-embed limit into continuation embeds of the same color
+fn split_fields(mut embed: WebhookEmbed) -> Vec<WebhookEmbed> {
+    let mut rest = if embed.fields.len() > FIELD_COUNT_LIMIT {
+        embed.fields.split_off(FIELD_COUNT_LIMIT)
+    } else {
+        vec![]
+    };
+    let color = embed.color;
+    let mut embeds = vec![embed];
+    while !rest.is_empty() {
+        let tail = rest.split_off(rest.len().min(FIELD_COUNT_LIMIT));
+        embeds.push(WebhookEmbed {
+            fields: rest,
+            color,
+            ..Default::default()
+        });
+        rest = tail;
+    }
+    embeds
+}
+
+fn field_chars(field: &WebhookField) -> usize {
+    field.name.chars().count() + field.value.chars().count()
+}
+
+fn embed_chars(embed: &WebhookEmbed) -> usize {
+    embed.title.chars().count()
+        + embed.description.chars().count()
+        + embed.author.as_ref().map_or(0, |a| a.name.chars().count())
+        + embed.fields.iter().map(field_chars).sum::<usize>()
+}
+
+fn is_empty_embed(embed: &WebhookEmbed) -> bool {
+    embed.title.is_empty()
+        && embed.description.is_empty()
+        && embed.fields.is_empty()
+        && embed.author.is_none()
+        && embed.thumbnail.is_none()
+}
+
+/// Formats mods as markdown links, one per line, ending with "...and N more" if they do not all
+/// fit in a field
+pub fn format_mod_list(mods: &[&Mod]) -> String {
+    let lines: Vec<String> = mods
         .iter()
-        .filter(|m| m.category == Some(category))
+        .map(|m| match (&m.name, &m.url) {
+            (Some(name), Some(url)) => format!("[{}]({})", name, url),
+            _ => "Hidden mod".to_string(),
+        })
         .collect();
-    // TODO: Very messy and probably broken
-    for (i, m) in filtered_mods.iter().enumerate() {
-        let formatted = if let (Some(url), Some(name)) = (m.url.as_ref(), m.name.as_ref()) {
-            format!("[{}]({})", name, url)
+    join_lines(&lines, FIELD_VALUE_LIMIT)
+}
+
+/// Joins as many leading `lines` as fit in `limit` characters, replacing the rest with a line
+/// saying how many were left out
+pub fn join_lines(lines: &[String], limit: usize) -> String {
+    let joined = lines.join("\n");
+    if joined.chars().count() <= limit {
+        return joined;
+    }
+
+    let lengths: Vec<usize> = lines.iter().map(|l| l.chars().count()).collect();
+    let mut shown = 0;
+    let mut len = 0;
+    for (i, line_len) in lengths.iter().enumerate() {
+        // lines are separated by newlines
+        let next = len + usize::from(i > 0) + line_len;
+        let rest = lines.len() - i - 1;
+        let suffix = if rest > 0 {
+            format!("\n...and {} more", rest).chars().count()
         } else {
-            "Hidden mod".to_string()
+            0
         };
-        if formatted.chars().count() + value.chars().count() > value.capacity() {
-            value.push_str(&format!("...and {} more", filtered_mods.len() - i));
+        if next + suffix > limit {
             break;
         }
-        value.push_str(&formatted);
-        value.push('\n');
+        len = next;
+        shown += 1;
     }
+
+    let mut value = lines[..shown].join("\n");
+    if shown < lines.len() {
+        if shown > 0 {
+            value.push('\n');
+        }
+        value.push_str(&format!("...and {} more", lines.len() - shown));
+    }
+    value
+}
+
+fn format_mod_field(mods: &[Mod], category: i32, name: &str) -> Option<WebhookField> {
+    let filtered_mods: Vec<&Mod> = mods
+        .iter()
+        .filter(|m| m.category == Some(category))
+        .collect();
+    let value = format_mod_list(&filtered_mods);
     if !value.is_empty() {
         Some(WebhookField {
             name: name.to_string(),
@@ -118,270 +299,1385 @@ fn format_mod_field(mods: &[Mod], category: i32, name: &str) -> Option<WebhookFi
     }
 }
 
-fn format_classes(classes: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"\d+;").unwrap();
-    }
-    let mut vec: Vec<&str> = RE
-        .find_iter(classes)
-        .map(|c| match c.as_str() {
-            "0;" => "<:driller:964680901621612584>",
-            "1;" => "<:engineer:964680922920255548>",
-            "2;" => "<:gunner:964680948530704404>",
-            "3;" => "<:scout:964680965521813524>",
-            _ => "<unknown>",
-        })
-        .collect();
-    while vec.len() < 4 {
-        vec.push("<:empty:964681045347823616>");
-    }
-    vec.join("")
-}
-
 pub async fn parse_response<T: serde::de::DeserializeOwned>(res: reqwest::Response) -> Result<T> {
-    let text = res.text().await?;
+    let text = res.text().await.map_err(|e| e.without_url())?;
     match serde_json::from_str::<T>(&text) {
         Ok(json) => Ok(json),
         Err(e) => Err(anyhow!("{}\nRaw string: {}", e, text)),
     }
 }
 
-pub async fn ratelimit_sleep(res: &reqwest::Response) {
-    let headers = res.headers();
-    if let (Some(remaining), Some(reset)) = (
-        headers
-            .get("x-ratelimit-remaining")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|s| s.parse::<i32>().ok()),
-        headers
-            .get("x-ratelimit-reset-after")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|s| s.parse::<f32>().ok()),
-    ) {
-        if remaining == 0 {
-            info!("Sleeping for: {}", reset);
-            tokio::time::sleep(tokio::time::Duration::from_secs_f32(reset)).await;
-        } else {
-            info!("Requests remaining: {}", remaining);
+/// Posts a new message to `webhook`
+pub async fn post_webhook(
+    limiter: &RateLimiter,
+    webhook: &str,
+    data: &WebhookBody,
+) -> Result<WebhookResponse> {
+    let request = limiter
+        .client()
+        .post(format!("{}?wait=true", webhook))
+        .json(data);
+    let route = ratelimit::route("POST", webhook);
+    parse_response(limiter.send(&route, request).await?).await
+}
+
+/// Message previously posted for a lobby
+struct TrackedMessage {
+    message_id: String,
+    content_hash: Option<String>,
+    last_edited: i64,
+    thread_id: Option<String>,
+    last_state: Option<String>,
+}
+
+/// Forum posts are limited to this many characters in their name
+const THREAD_NAME_LIMIT: usize = 100;
+
+/// The parts of a lobby change notes are posted about, stored as JSON in
+/// `discord_message.last_state`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LobbyState {
+    hazard: i64,
+    in_mission: bool,
+    /// Number of players of each class
+    classes: [usize; 4],
+    /// Names of the mods by id
+    mods: BTreeMap<i64, String>,
+}
+
+impl LobbyState {
+    fn of(lobby: &Lobby) -> Self {
+        let mut classes = [0; 4];
+        for class in lobby.classes.split(';').filter_map(Class::parse) {
+            classes[class as usize] += 1;
         }
+        Self {
+            hazard: lobby.hazard(),
+            in_mission: lobby.in_mission(),
+            classes,
+            mods: lobby
+                .mods
+                .iter()
+                .map(|m| {
+                    let name = m
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("Hidden mod ({})", m.id));
+                    (m.id, name)
+                })
+                .collect(),
+        }
+    }
+
+    /// Describes what changed since `old`, one line per change
+    fn changes_since(&self, old: &LobbyState) -> Vec<String> {
+        let mut notes = vec![];
+        if self.in_mission != old.in_mission {
+            notes.push(if self.in_mission {
+                "Mission started".to_string()
+            } else {
+                "Returned to the Space Rig".to_string()
+            });
+        }
+        if self.hazard != old.hazard {
+            notes.push(format!(
+                "Hazard changed from {} to {}",
+                old.hazard, self.hazard
+            ));
+        }
+        for (class, (&new, &old)) in Class::ALL.iter().zip(self.classes.iter().zip(&old.classes)) {
+            if new < old {
+                notes.push(format!("{} slot opened", class.name()));
+            } else if new > old {
+                notes.push(format!("{} joined", class.name()));
+            }
+        }
+        if self.mods != old.mods {
+            notes.push("Mods changed".to_string());
+            for (id, name) in &self.mods {
+                if !old.mods.contains_key(id) {
+                    notes.push(format!("+ {}", name));
+                }
+            }
+            for (id, name) in &old.mods {
+                if !self.mods.contains_key(id) {
+                    notes.push(format!("- {}", name));
+                }
+            }
+        }
+        notes
+    }
+}
+
+/// Prints an action that dry-run mode skipped instead of sending it to Discord
+fn print_action(
+    target: &DiscordTarget,
+    action: &str,
+    message_id: Option<&str>,
+    data: Option<&WebhookBody>,
+) -> Result<()> {
+    match message_id {
+        Some(id) => println!("[{}] {} {}", target.name, action, id),
+        None => println!("[{}] {}", target.name, action),
+    }
+    if let Some(data) = data {
+        println!("{}", serde_json::to_string_pretty(data)?);
+    }
+    Ok(())
+}
+
+/// Hash of the serialized body, used to skip edits that would not change the message
+fn body_hash(data: &WebhookBody) -> Result<String> {
+    Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(data)?)))
+}
+
+fn closed_body(
+    server: &Lobby,
+    lifetime: i64,
+    peak_players: i64,
+    avatar_url: Option<String>,
+) -> WebhookBody {
+    let mut fields = vec![
+        WebhookField {
+            name: "Region".to_string(),
+            value: server.region.clone(),
+            inline: true,
+        },
+        WebhookField {
+            name: "Difficulty".to_string(),
+            value: format!("Hazard {}", server.hazard()),
+            inline: true,
+        },
+        WebhookField {
+            name: "Lifetime".to_string(),
+            value: format_duration(lifetime),
+            inline: true,
+        },
+        WebhookField {
+            name: "Peak players".to_string(),
+            value: peak_players.to_string(),
+            inline: true,
+        },
+    ];
+
+    if let Some(field) = format_mod_field(&server.mods, 0, "Verified Mods") {
+        fields.push(field)
+    }
+    if let Some(field) = format_mod_field(&server.mods, 1, "Approved Mods") {
+        fields.push(field)
+    }
+    if let Some(field) = format_mod_field(&server.mods, 2, "Sandboxed Mods") {
+        fields.push(field)
+    }
+
+    WebhookBody {
+        avatar_url,
+        embeds: vec![WebhookEmbed {
+            title: server.server_name.clone(),
+            description: "Lobby closed".to_string(),
+            color: Some(CLOSED_COLOR),
+            fields,
+            ..Default::default()
+        }],
+        ..Default::default()
     }
+    .fit_limits()
 }
 
+fn format_duration(seconds: i64) -> String {
+    let minutes = seconds / 60;
+    if minutes < 60 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    }
+}
+
+/// Updates the lobby messages of every target. With `dry_run` the messages that would be posted,
+/// edited or deleted are printed instead and `discord_message` is left untouched.
 #[tracing::instrument(skip_all)]
-pub async fn update_discord(pool: &SqlitePool) -> Result<()> {
-    let webhook = &std::env::var("DISCORD_WEBHOOK").unwrap();
-    let steam_key = &std::env::var("STEAM_WEB_KEY").unwrap();
-
-    let res = sqlx::query!(
-        r#"SELECT time,
-            datetime(time, 'unixepoch', 'localtime') AS "time_formatted!: String",
-            lobby_id,
-            diff,
-            region,
-            host_user_id,
-            server_name,
-            classes,
-            start,
-            (SELECT json_group_array(json_object('id', mod_id, 'category', category, 'name', name, 'url', url)) FROM
-                (SELECT mod_id, category, name, url
-                FROM server_mod
-                JOIN mod USING(mod_id)
-                WHERE
-                    server_mod.time = server.time
-                    AND server_mod.lobby_id = server.lobby_id
-                ORDER BY category)
-            ) AS "mods?: String",
-            (SELECT message_id FROM discord_message WHERE server.lobby_id = discord_message.lobby_id) AS "message_id?" -- use subquery because sqlx can't handle left join
-            FROM server
-            WHERE (server.time, server.lobby_id) IN (
-                SELECT time, lobby_id
-                FROM server
-                JOIN server_mod USING(time, lobby_id)
-                WHERE
-                    mod_id IN (
-                        1861561 -- Custom Difficulty
-                    )
-                    AND (server.time, server.lobby_id) NOT IN (
-                        SELECT MAX(time), lobby_id
-                        FROM server_mod
-                        WHERE mod_id IN (
-                            2093114, -- Mission Content Randomizer
-                            1034411, -- 2x flashlight
-                            1034683, -- 3x flashlight
-                            1034060, -- 5x flashlight
-                            1176984, -- better minigun
-                            1159061 -- better scout
-                        )
-                        GROUP BY lobby_id
-                    )
-                    AND (server.time, server.lobby_id) IN (
-                        SELECT MAX(time), lobby_id
-                        FROM server
-                        WHERE time > strftime('%s', datetime('now', '-10 minutes'))
-                        GROUP BY lobby_id
-                    )
-            )
-            ORDER BY time
-        "#,
+pub async fn update_discord(
+    pool: &SqlitePool,
+    settings: &DiscordSettings,
+    limiter: &RateLimiter,
+    now: i64,
+    dry_run: bool,
+) -> Result<()> {
+    let moderation = Moderation::load(pool, now).await?;
+    let mut lobbies = vec![];
+    // lobbies hidden by moderation rules, their messages are deleted rather than closed
+    let mut blocked = HashSet::new();
+    for target in &settings.targets {
+        let mut matching = vec![];
+        for lobby in latest_lobbies(pool, target.filter.window_minutes).await? {
+            if moderation.hides(&lobby.host_user_id, &lobby.lobby_id, &lobby.server_name) {
+                blocked.insert(lobby.lobby_id);
+            } else if target.filter.matches(&lobby) {
+                matching.push(lobby);
+            }
+        }
+        lobbies.push(matching);
+    }
+
+    // hosts are looked up in batches once and shared between targets
+    let hosts: Vec<&str> = lobbies
+        .iter()
+        .flatten()
+        .map(|l| l.host_user_id.as_str())
+        .collect();
+    let players = get_players(pool, &hosts).await?;
+
+    // a failing target must not keep the others from being updated
+    let results = join_all(
+        settings
+            .targets
+            .iter()
+            .zip(&lobbies)
+            .map(|(target, lobbies)| {
+                update_target(
+                    pool, target, lobbies, &blocked, limiter, &players, now, dry_run,
+                )
+            }),
+    )
+    .await;
+    let mut failed = vec![];
+    for (target, res) in settings.targets.iter().zip(results) {
+        if let Err(e) = res {
+            warn!("failed to update target {}: {:#}", target.name, e);
+            failed.push(target.name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed to update targets {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(target = target.name))]
+#[allow(clippy::too_many_arguments)]
+async fn update_target(
+    pool: &SqlitePool,
+    target: &DiscordTarget,
+    lobbies: &[Lobby],
+    blocked: &HashSet<String>,
+    limiter: &RateLimiter,
+    players: &HashMap<String, Player>,
+    now: i64,
+    dry_run: bool,
+) -> Result<()> {
+    let sink = sink::for_target(target, limiter, dry_run)?;
+    let sink = sink.as_ref();
+
+    let messages: HashMap<String, TrackedMessage> = sqlx::query!(
+        r#"SELECT lobby_id AS "lobby_id!", message_id, content_hash, last_edited, thread_id, last_state FROM discord_message WHERE target = ? AND kind = 'lobby'"#,
+        target.name
     )
     .fetch_all(pool)
-    .await.unwrap();
+    .await?
+    .into_iter()
+    .map(|r| {
+        (
+            r.lobby_id,
+            TrackedMessage {
+                message_id: r.message_id,
+                content_hash: r.content_hash,
+                last_edited: r.last_edited,
+                thread_id: r.thread_id,
+                last_state: r.last_state,
+            },
+        )
+    })
+    .collect();
 
-    let filter = std::env::var("SERVER_NAME_FILTER");
-    for server in res {
-        if let Ok(filter) = &filter {
-            let name_lower = server.server_name.to_lowercase();
-            if filter.split_whitespace().any(|f| name_lower.contains(f)) {
+    for server in lobbies {
+        let message = messages.get(&server.lobby_id);
+        let message_id = message.map(|m| &m.message_id);
+        let thread_id = message.and_then(|m| m.thread_id.as_deref());
+
+        let mut data = target
+            .embed
+            .render(server, players.get(&server.host_user_id))?;
+        // the hash leaves out mentions, edits do not change the content of a message
+        let hash = body_hash(&data)?;
+
+        let state = LobbyState::of(server);
+        let state_json = serde_json::to_string(&state)?;
+        if let (Some(message), Some(thread_id)) = (message, thread_id) {
+            let old = message
+                .last_state
+                .as_deref()
+                .and_then(|s| serde_json::from_str::<LobbyState>(s).ok());
+            if let Some(old) = old.filter(|old| *old != state) {
+                post_changes(
+                    pool, sink, target, message, thread_id, &old, &state, dry_run,
+                )
+                .await?;
+            }
+        }
+
+        if let Some(message) = message {
+            let heartbeat_due = target
+                .heartbeat_minutes
+                .is_some_and(|minutes| now - message.last_edited >= minutes * 60);
+            if message.content_hash.as_deref() == Some(hash.as_str()) && !heartbeat_due {
+                if dry_run {
+                    print_action(target, "UNCHANGED", Some(&message.message_id), None)?;
+                    continue;
+                }
+                // nothing changed, only record that the lobby still matches
+                sqlx::query!(
+                    "UPDATE discord_message SET last_updated = ? WHERE message_id = ?",
+                    now,
+                    message.message_id
+                )
+                .execute(pool)
+                .await?;
                 continue;
             }
         }
 
-        let mods = server
-            .mods
-            .map_or_else(|| Ok(vec![]), |m| serde_json::from_str::<Vec<Mod>>(&m))
-            .unwrap();
+        if message_id.is_none() {
+            if target.forum {
+                let name = match server.server_name.trim() {
+                    "" => format!("Lobby {}", server.lobby_id),
+                    name => name.to_string(),
+                };
+                data.thread_name = Some(truncate_chars(&name, THREAD_NAME_LIMIT));
+            }
+            if let Some(mentions) =
+                pending_mentions(pool, target, &server.host_user_id, now).await?
+            {
+                data.content = Some(mention_content(&mentions));
+                data.allowed_mentions = Some(mentions);
+            }
+        }
 
-        let mut fields = vec![
-            WebhookField {
-                name: "Region".to_string(),
-                value: server.region,
-                inline: true,
-            },
-            WebhookField {
-                name: "Difficulty".to_string(),
-                value: format!("Hazard {}", server.diff + 1),
-                inline: true,
-            },
-            WebhookField {
-                name: "Classes".to_string(),
-                value: format_classes(&server.classes),
-                inline: true,
-            },
-            WebhookField {
-                name: "Status".to_string(),
-                value: (if server.start.is_empty() {
-                    "In Space Rig".to_string()
-                } else {
-                    "In Mission".to_string()
-                }),
-                inline: false,
-            },
-            /*WebhookField {
-                name: "\u{200B}".to_string(),
-                value: "\u{200B}".to_string(),
-                inline: false,
-            },*/
-        ];
+        if dry_run {
+            match message_id {
+                Some(id) => print_action(target, "PATCH", Some(id), Some(&data))?,
+                None => print_action(target, "POST", None, Some(&data))?,
+            }
+            continue;
+        }
 
-        if let Some(field) = format_mod_field(&mods, 0, "Verified Mods") {
-            fields.push(field)
+        match sink
+            .send(message_id.map(String::as_str), thread_id, &data)
+            .await?
+        {
+            Delivery::Sent { id, thread_id } => {
+                sqlx::query!("INSERT INTO discord_message(target, message_id, lobby_id, last_updated, last_edited, content_hash, thread_id, last_state) VALUES (?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT(message_id) DO UPDATE SET last_updated = excluded.last_updated, last_edited = excluded.last_edited, content_hash = excluded.content_hash, last_state = excluded.last_state, closed_at = NULL;", target.name, id, server.lobby_id, now, now, hash, thread_id, state_json)
+                    .execute(pool)
+                    .await?;
+                if let Some(mentions) = &data.allowed_mentions {
+                    record_mentions(pool, target, mentions, &server.host_user_id, now).await?;
+                }
+            }
+            Delivery::Gone => {
+                if let Some(id) = message_id {
+                    warn!("Tried to update unknown message. Deleting...");
+                    sqlx::query!("DELETE FROM discord_message WHERE message_id = ?", id)
+                        .execute(pool)
+                        .await?;
+                }
+            }
+            Delivery::Failed => {}
+        }
+    }
+
+    for (lobby_id, message) in &messages {
+        if blocked.contains(lobby_id) {
+            delete_message(
+                pool,
+                sink,
+                target,
+                &message.message_id,
+                message.thread_id.as_deref(),
+                dry_run,
+            )
+            .await?;
         }
-        if let Some(field) = format_mod_field(&mods, 1, "Approved Mods") {
-            fields.push(field)
+    }
+
+    if target.status_board {
+        update_board(pool, sink, target, lobbies, now, dry_run).await?;
+    }
+
+    // lobbies that have not matched for a while are gone, either closed or filtered out while
+    // still being up
+    let gone_before = now - GONE_AFTER_MINUTES * 60;
+    let gone = sqlx::query!(
+        r#"SELECT message_id,
+            lobby_id AS "lobby_id!",
+            thread_id,
+            EXISTS (SELECT 1
+                FROM server
+                WHERE server.lobby_id = discord_message.lobby_id
+                    AND time = (SELECT MAX(time) FROM server)
+            ) AS "still_up!: bool"
+            FROM discord_message
+            WHERE target = ? AND kind = 'lobby' AND closed_at IS NULL AND last_updated <= ?
+        "#,
+        target.name,
+        gone_before,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for message in gone {
+        // a lobby that is still up but no longer matches the filter did not close, so its
+        // message is not turned into a summary
+        if message.still_up || matches!(target.on_close, ClosedBehavior::Delete) {
+            delete_message(
+                pool,
+                sink,
+                target,
+                &message.message_id,
+                message.thread_id.as_deref(),
+                dry_run,
+            )
+            .await?;
+        } else {
+            close_message(
+                pool,
+                sink,
+                target,
+                &message.message_id,
+                message.thread_id.as_deref(),
+                &message.lobby_id,
+                now,
+                dry_run,
+            )
+            .await?;
         }
-        if let Some(field) = format_mod_field(&mods, 2, "Sandboxed Mods") {
-            fields.push(field)
+    }
+
+    if let ClosedBehavior::Close {
+        delete_after_minutes: Some(minutes),
+    } = target.on_close
+    {
+        let expired = now - minutes * 60;
+        let res = sqlx::query!(
+            "SELECT message_id, thread_id FROM discord_message WHERE target = ? AND closed_at <= ?",
+            target.name,
+            expired,
+        )
+        .fetch_all(pool)
+        .await?;
+        for message in res {
+            delete_message(
+                pool,
+                sink,
+                target,
+                &message.message_id,
+                message.thread_id.as_deref(),
+                dry_run,
+            )
+            .await?;
         }
+    }
+
+    Ok(())
+}
 
-        let result: SteamPlayerRequest = parse_response(reqwest::Client::new()
-                .get(format!("https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v0002/?key={}&steamids={}", steam_key, server.host_user_id))
-                .send()
-                .await?
-            ).await?;
+/// Edits `message_id` if set, otherwise posts a new message. Messages in a forum post need the
+/// post's `thread_id`.
+async fn send_message(
+    limiter: &RateLimiter,
+    webhook: &str,
+    message_id: Option<&str>,
+    thread_id: Option<&str>,
+    data: &WebhookBody,
+) -> Result<WebhookResponse> {
+    let thread = thread_query(thread_id);
+    let client = limiter.client();
+    let (route, request) = if let Some(message) = message_id {
+        (
+            ratelimit::route("PATCH", webhook),
+            client.patch(format!(
+                "{}/messages/{}?wait=true{}",
+                webhook, message, thread
+            )),
+        )
+    } else {
+        (
+            ratelimit::route("POST", webhook),
+            client.post(format!("{}?wait=true{}", webhook, thread)),
+        )
+    };
+    parse_response(limiter.send(&route, request.json(data)).await?).await
+}
 
-        let player = &result.response.players[0];
+fn thread_query(thread_id: Option<&str>) -> String {
+    thread_id
+        .map(|t| format!("&thread_id={}", t))
+        .unwrap_or_default()
+}
 
-        let data = WebhookBody {
-            avatar_url: Some("https://cdn.discordapp.com/attachments/878318716801155236/968174640847523930/engo.png".to_string()),
-            embeds: vec![
-                WebhookEmbed {
-                    title: server.server_name,
-                    author: WebhookAuthor {
-                        name: player.personaname.to_owned(),
-                        icon_url: player.avatarfull.to_owned(),
-                        url: format!("https://steamcommunity.com/profiles/{}", server.host_user_id),
-                    },
-                    description: format!("steam://joinlobby/548430/{}/{}", server.lobby_id, server.host_user_id),
-                    fields,
-                }
-            ]
-        };
+/// Delivers messages through a Discord webhook. Messages in a forum channel open a forum post
+/// when they have a `thread_name`.
+pub struct DiscordSink<'a> {
+    limiter: &'a RateLimiter,
+    webhook: String,
+}
 
-        let mut success = false;
+impl<'a> DiscordSink<'a> {
+    pub fn new(limiter: &'a RateLimiter, webhook: String) -> Self {
+        Self { limiter, webhook }
+    }
+}
 
-        while !success {
-            let client = reqwest::Client::new();
-            let res = if let Some(message) = &server.message_id {
-                client.patch(format!("{}/messages/{}?wait=true", webhook, message))
+#[async_trait]
+impl Sink for DiscordSink<'_> {
+    async fn send(
+        &self,
+        message_id: Option<&str>,
+        thread_id: Option<&str>,
+        body: &WebhookBody,
+    ) -> Result<Delivery> {
+        let res = send_message(self.limiter, &self.webhook, message_id, thread_id, body).await?;
+        Ok(match res {
+            WebhookResponse::Success { id, channel_id } => {
+                // a new forum post is a thread of its own, the thread of an edit stays the same
+                let thread_id = match thread_id {
+                    Some(thread_id) => Some(thread_id.to_string()),
+                    None => channel_id.filter(|_| body.thread_name.is_some()),
+                };
+                Delivery::Sent { id, thread_id }
+            }
+            WebhookResponse::Error { code: 10008, .. } if message_id.is_some() => Delivery::Gone,
+            WebhookResponse::Error { message, code } => {
+                warn!("Received error from endpoint: {} code: {}", message, code);
+                Delivery::Failed
+            }
+        })
+    }
+
+    async fn delete(&self, message_id: &str, thread_id: Option<&str>) -> Result<()> {
+        let mut url = format!("{}/messages/{}", self.webhook, message_id);
+        if let Some(thread_id) = thread_id {
+            url += &format!("?thread_id={}", thread_id);
+        }
+        let request = self.limiter.client().delete(url);
+        let res = self
+            .limiter
+            .send(&ratelimit::route("DELETE", &self.webhook), request)
+            .await?;
+        // already deleted messages are gone all the same
+        if !res.status().is_success() && res.status() != reqwest::StatusCode::NOT_FOUND {
+            bail!("failed to delete {}: {}", message_id, res.status());
+        }
+        Ok(())
+    }
+}
+
+/// Posts what changed about a lobby into its forum post
+#[allow(clippy::too_many_arguments)]
+async fn post_changes(
+    pool: &SqlitePool,
+    sink: &dyn Sink,
+    target: &DiscordTarget,
+    message: &TrackedMessage,
+    thread_id: &str,
+    old: &LobbyState,
+    state: &LobbyState,
+    dry_run: bool,
+) -> Result<()> {
+    let data = WebhookBody {
+        avatar_url: target.embed.avatar_url(),
+        content: Some(join_lines(&state.changes_since(old), CONTENT_LIMIT)),
+        allowed_mentions: Some(AllowedMentions::default()),
+        ..Default::default()
+    };
+    if dry_run {
+        return print_action(target, "NOTE in thread", Some(thread_id), Some(&data));
+    }
+
+    if let Delivery::Sent { .. } = sink.send(None, Some(thread_id), &data).await? {
+        // recorded straight away so a failing edit does not repeat the notes
+        let state_json = serde_json::to_string(state)?;
+        sqlx::query!(
+            "UPDATE discord_message SET last_state = ? WHERE message_id = ?",
+            state_json,
+            message.message_id
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+fn board_body(target: &DiscordTarget, lobbies: &[Lobby]) -> WebhookBody {
+    let lines: Vec<String> = lobbies
+        .iter()
+        .map(|l| {
+            format!(
+                "`H{}` {} · {}/4 {} **{}**\nsteam://joinlobby/548430/{}/{}",
+                l.hazard(),
+                l.region,
+                l.numplayers,
+                target.embed.emoji.format(&l.classes).join(""),
+                l.server_name,
+                l.lobby_id,
+                l.host_user_id
+            )
+        })
+        .collect();
+
+    let fields = if lobbies.is_empty() {
+        vec![]
+    } else {
+        let field = |name: &str, value: String| WebhookField {
+            name: name.to_string(),
+            value,
+            inline: true,
+        };
+        let players: i64 = lobbies.iter().map(|l| l.numplayers).sum();
+        let in_mission = lobbies.iter().filter(|l| l.in_mission()).count();
+        vec![
+            field("Lobbies", lobbies.len().to_string()),
+            field("Players", players.to_string()),
+            field("In mission", in_mission.to_string()),
+        ]
+    };
+
+    WebhookBody {
+        avatar_url: target.embed.avatar_url(),
+        embeds: vec![WebhookEmbed {
+            title: "Current lobbies".to_string(),
+            description: if lobbies.is_empty() {
+                "No lobbies right now".to_string()
             } else {
-                client.post(format!("{}?wait=true", webhook))
+                join_lines(&lines, DESCRIPTION_LIMIT)
+            },
+            fields,
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+    .fit_limits()
+}
+
+/// Keeps the status board of `target` listing the lobbies currently matching it
+async fn update_board(
+    pool: &SqlitePool,
+    sink: &dyn Sink,
+    target: &DiscordTarget,
+    lobbies: &[Lobby],
+    now: i64,
+    dry_run: bool,
+) -> Result<()> {
+    let mut data = board_body(target, lobbies);
+    let hash = body_hash(&data)?;
+
+    let board = sqlx::query!(
+        "SELECT message_id, content_hash, last_edited, thread_id FROM discord_message WHERE target = ? AND kind = 'board'",
+        target.name
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(board) = &board {
+        let heartbeat_due = target
+            .heartbeat_minutes
+            .is_some_and(|minutes| now - board.last_edited >= minutes * 60);
+        if board.content_hash.as_deref() == Some(hash.as_str()) && !heartbeat_due {
+            if dry_run {
+                return print_action(target, "UNCHANGED", Some(&board.message_id), None);
             }
-            .json(&data)
-            .send()
+            sqlx::query!(
+                "UPDATE discord_message SET last_updated = ? WHERE message_id = ?",
+                now,
+                board.message_id
+            )
+            .execute(pool)
             .await?;
-            ratelimit_sleep(&res).await;
+            return Ok(());
+        }
+    }
 
-            let result: WebhookResponse = parse_response(res).await?;
+    let message_id = board.as_ref().map(|b| b.message_id.as_str());
+    let thread_id = board.as_ref().and_then(|b| b.thread_id.as_deref());
+    if target.forum && message_id.is_none() {
+        data.thread_name = Some("Current lobbies".to_string());
+    }
+    if dry_run {
+        return match message_id {
+            Some(id) => print_action(target, "PATCH", Some(id), Some(&data)),
+            None => print_action(target, "POST", None, Some(&data)),
+        };
+    }
 
-            match result {
-                WebhookResponse::Success { id } => {
-                    sqlx::query!("INSERT INTO discord_message(message_id, lobby_id, last_updated) VALUES (?, ?, strftime('%s', 'now')) ON CONFLICT(message_id) DO UPDATE SET last_updated = excluded.last_updated;", id, server.lobby_id)
-                        .execute(pool)
-                        .await?;
-                    success = true;
-                }
-                WebhookResponse::Ratelimit {
-                    message,
-                    global,
-                    retry_after,
-                } => {
-                    info!(
-                        "{}; global: {}, retry_after: {}",
-                        message, global, retry_after
-                    );
-                    tokio::time::sleep(tokio::time::Duration::from_secs_f32(retry_after)).await;
-                }
-                WebhookResponse::Error { message, code } => {
-                    if code == 10008 {
-                        if let Some(id) = &server.message_id {
-                            warn!("Tried to update unknown message. Deleting...");
-                            sqlx::query!("DELETE FROM discord_message WHERE message_id = ?", id)
-                                .execute(pool)
-                                .await?;
-                        }
-                    } else {
-                        warn!("Received error from endpoint: {} code: {}", message, code);
-                    }
-                    success = true;
-                }
+    match sink.send(message_id, thread_id, &data).await? {
+        Delivery::Sent { id, thread_id } => {
+            sqlx::query!("INSERT INTO discord_message(target, kind, message_id, last_updated, last_edited, content_hash, thread_id) VALUES (?, 'board', ?, ?, ?, ?, ?) ON CONFLICT(message_id) DO UPDATE SET last_updated = excluded.last_updated, last_edited = excluded.last_edited, content_hash = excluded.content_hash", target.name, id, now, now, hash, thread_id)
+                .execute(pool)
+                .await?;
+        }
+        Delivery::Gone => {
+            // the board was deleted, post a new one next time
+            if let Some(id) = message_id {
+                sqlx::query!("DELETE FROM discord_message WHERE message_id = ?", id)
+                    .execute(pool)
+                    .await?;
             }
         }
+        Delivery::Failed => {}
+    }
+    Ok(())
+}
+
+/// Roles and users to ping about a new lobby of `host`, leaving out those still cooling down
+async fn pending_mentions(
+    pool: &SqlitePool,
+    target: &DiscordTarget,
+    host: &str,
+    now: i64,
+) -> Result<Option<AllowedMentions>> {
+    let mentions = &target.mentions;
+    if mentions.roles.is_empty() && mentions.users.is_empty() {
+        return Ok(None);
     }
 
-    let res = sqlx::query!(
-        r#"SELECT message_id
-            FROM discord_message
-            WHERE last_updated <= strftime('%s', datetime('now', '-10 minutes'))
+    let host_since = now - mentions.host_cooldown_minutes * 60;
+    let since = now - mentions.cooldown_minutes * 60;
+    let cooling: HashSet<(String, String)> = sqlx::query!(
+        r#"SELECT kind, id
+            FROM discord_mention
+            WHERE target = ? AND last_mentioned > CASE kind WHEN 'host' THEN ? ELSE ? END
         "#,
+        target.name,
+        host_since,
+        since,
     )
     .fetch_all(pool)
-    .await
-    .unwrap();
+    .await?
+    .into_iter()
+    .map(|r| (r.kind, r.id))
+    .collect();
+    let is_cooling = |kind: &str, id: &str| cooling.contains(&(kind.to_string(), id.to_string()));
 
-    for message in res {
-        let res = reqwest::Client::new()
-            .delete(format!("{}/messages/{}", webhook, message.message_id))
-            .send()
-            .await?;
-        ratelimit_sleep(&res).await;
+    if is_cooling("host", host) {
+        return Ok(None);
+    }
+    let roles: Vec<String> = mentions
+        .roles
+        .iter()
+        .filter(|r| !is_cooling("role", r))
+        .cloned()
+        .collect();
+    let users: Vec<String> = mentions
+        .users
+        .iter()
+        .filter(|u| !is_cooling("user", u))
+        .cloned()
+        .collect();
+    if roles.is_empty() && users.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(AllowedMentions {
+        parse: vec![],
+        roles,
+        users,
+    }))
+}
+
+fn mention_content(mentions: &AllowedMentions) -> String {
+    mentions
+        .roles
+        .iter()
+        .map(|r| format!("<@&{}>", r))
+        .chain(mentions.users.iter().map(|u| format!("<@{}>", u)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
+/// Starts the cooldowns of everyone pinged and of the host that caused it
+async fn record_mentions(
+    pool: &SqlitePool,
+    target: &DiscordTarget,
+    mentions: &AllowedMentions,
+    host: &str,
+    now: i64,
+) -> Result<()> {
+    let ids = mentions
+        .roles
+        .iter()
+        .map(|r| ("role", r.as_str()))
+        .chain(mentions.users.iter().map(|u| ("user", u.as_str())))
+        .chain([("host", host)]);
+    for (kind, id) in ids {
         sqlx::query!(
-            "DELETE FROM discord_message WHERE message_id = ?",
-            message.message_id
+            "INSERT INTO discord_mention(target, kind, id, last_mentioned) VALUES (?, ?, ?, ?) ON CONFLICT(target, kind, id) DO UPDATE SET last_mentioned = excluded.last_mentioned",
+            target.name,
+            kind,
+            id,
+            now,
         )
         .execute(pool)
         .await?;
     }
+    Ok(())
+}
+
+async fn delete_message(
+    pool: &SqlitePool,
+    sink: &dyn Sink,
+    target: &DiscordTarget,
+    message_id: &str,
+    thread_id: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        return print_action(target, "DELETE", Some(message_id), None);
+    }
+
+    // the message stays tracked and is deleted on a later run, one failure must not keep the
+    // other messages from being updated
+    if let Err(e) = sink.delete(message_id, thread_id).await {
+        warn!("failed to delete message {}: {:#}", message_id, e);
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "DELETE FROM discord_message WHERE message_id = ?",
+        message_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Replaces the message of a lobby that is gone with a summary of the lobby
+#[allow(clippy::too_many_arguments)]
+async fn close_message(
+    pool: &SqlitePool,
+    sink: &dyn Sink,
+    target: &DiscordTarget,
+    message_id: &str,
+    thread_id: Option<&str>,
+    lobby_id: &str,
+    now: i64,
+    dry_run: bool,
+) -> Result<()> {
+    let Some(lobby) = latest_snapshot(pool, lobby_id).await? else {
+        return delete_message(pool, sink, target, message_id, thread_id, dry_run).await;
+    };
+    let history = sqlx::query!(
+        r#"SELECT MIN(time) AS "first_seen!: i64",
+            MAX(time) AS "last_seen!: i64",
+            MAX(numplayers) AS "peak_players!: i64"
+            FROM server
+            WHERE lobby_id = ?
+        "#,
+        lobby_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let data = closed_body(
+        &lobby,
+        history.last_seen - history.first_seen,
+        history.peak_players,
+        target.embed.avatar_url(),
+    );
+    if dry_run {
+        return print_action(target, "PATCH", Some(message_id), Some(&data));
+    }
+    let hash = body_hash(&data)?;
+
+    match sink.send(Some(message_id), thread_id, &data).await? {
+        Delivery::Sent { .. } => {
+            sqlx::query!(
+                "UPDATE discord_message SET closed_at = ?, last_edited = ?, content_hash = ? WHERE message_id = ?",
+                now,
+                now,
+                hash,
+                message_id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Delivery::Gone => {
+            sqlx::query!(
+                "DELETE FROM discord_message WHERE message_id = ?",
+                message_id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Delivery::Failed => {}
+    }
+    Ok(())
+}
 
+/// Prints whether each recent lobby matches the filter of each Discord target and why
+pub async fn explain_filter(pool: &SqlitePool, settings: &DiscordSettings, now: i64) -> Result<()> {
+    let moderation = Moderation::load(pool, now).await?;
+    for target in &settings.targets {
+        println!("target {}:", target.name);
+        for lobby in latest_lobbies(pool, target.filter.window_minutes).await? {
+            if moderation.is_opted_out(&lobby.host_user_id) {
+                println!(
+                    "  BLOCK {} ({}) as its host opted out",
+                    lobby.server_name, lobby.lobby_id
+                );
+                continue;
+            }
+            if let Some(rule) =
+                moderation.blocking_rule(&lobby.host_user_id, &lobby.lobby_id, &lobby.server_name)
+            {
+                println!(
+                    "  BLOCK {} ({}) by moderation rule {}: {}",
+                    lobby.server_name, lobby.lobby_id, rule.rule_id, rule.reason
+                );
+                continue;
+            }
+            let verdict = target.filter.evaluate(&lobby);
+            println!(
+                "  {} {} ({})",
+                if verdict.matched() { "MATCH" } else { "SKIP " },
+                lobby.server_name,
+                lobby.lobby_id
+            );
+            for check in verdict.checks {
+                println!(
+                    "      [{}] {}",
+                    if check.passed { "pass" } else { "FAIL" },
+                    check.description
+                );
+            }
+        }
+    }
     Ok(())
 }
+
+/// Posts pending mod category changes to `DISCORD_CATEGORY_WEBHOOK` if it is set
+#[tracing::instrument(skip_all)]
+pub async fn post_category_events(pool: &SqlitePool, limiter: &RateLimiter) -> Result<()> {
+    let Some(webhook) = std::env::var("DISCORD_CATEGORY_WEBHOOK")
+        .ok()
+        .filter(|w| !w.is_empty())
+    else {
+        return Ok(());
+    };
+
+    let events = sqlx::query!(
+        r#"SELECT event_id, mod_id, name, url, old_category, new_category
+            FROM mod_category_event
+            JOIN mod USING(mod_id)
+            WHERE notified = 0
+            ORDER BY event_id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for event in events {
+        let data = WebhookBody {
+            avatar_url: None,
+            embeds: vec![WebhookEmbed {
+                title: event
+                    .name
+                    .unwrap_or_else(|| format!("Hidden mod ({})", event.mod_id)),
+                url: event.url,
+                description: format!(
+                    "Moved from **{}** to **{}**",
+                    category_name(event.old_category),
+                    category_name(event.new_category)
+                ),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .fit_limits();
+
+        match post_webhook(limiter, &webhook, &data).await? {
+            WebhookResponse::Success { .. } => {
+                sqlx::query!(
+                    "UPDATE mod_category_event SET notified = 1 WHERE event_id = ?",
+                    event.event_id
+                )
+                .execute(pool)
+                .await?;
+            }
+            WebhookResponse::Error { message, code } => {
+                warn!("Received error from endpoint: {} code: {}", message, code);
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Announces mods seen in a public lobby for the first time to `DISCORD_NEW_MOD_WEBHOOK` if it is
+/// set. Mods are held back until `update_mods` has fetched their mod.io metadata.
+#[tracing::instrument(skip_all)]
+pub async fn announce_new_mods(pool: &SqlitePool, limiter: &RateLimiter) -> Result<()> {
+    let Some(webhook) = std::env::var("DISCORD_NEW_MOD_WEBHOOK")
+        .ok()
+        .filter(|w| !w.is_empty())
+    else {
+        return Ok(());
+    };
+
+    let mods = sqlx::query!(
+        r#"SELECT mod_id,
+            name,
+            url,
+            json_extract(metadata, '$.summary') AS "summary?: String",
+            json_extract(metadata, '$.logo.thumb_320x180') AS "logo?: String",
+            first_seen AS "first_seen!: i64",
+            (SELECT server_name
+                FROM server
+                WHERE server.time = mod.first_seen AND server.lobby_id = mod.first_lobby_id
+            ) AS "server_name?: String"
+            FROM mod
+            WHERE announced = 0 AND metadata IS NOT NULL AND first_seen IS NOT NULL
+            ORDER BY first_seen
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for m in mods {
+        let mut fields = vec![];
+        if let Some(server_name) = m.server_name {
+            fields.push(WebhookField {
+                name: "Seen in".to_string(),
+                value: server_name,
+                inline: true,
+            });
+        }
+        fields.push(WebhookField {
+            name: "First seen".to_string(),
+            value: format!("<t:{}:R>", m.first_seen),
+            inline: true,
+        });
+
+        let data = WebhookBody {
+            avatar_url: None,
+            embeds: vec![WebhookEmbed {
+                title: m
+                    .name
+                    .unwrap_or_else(|| format!("Hidden mod ({})", m.mod_id)),
+                url: m.url,
+                description: m.summary.unwrap_or_default(),
+                fields,
+                thumbnail: m.logo.map(|url| WebhookImage { url }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .fit_limits();
+
+        match post_webhook(limiter, &webhook, &data).await? {
+            WebhookResponse::Success { .. } => {
+                sqlx::query!("UPDATE mod SET announced = 1 WHERE mod_id = ?", m.mod_id)
+                    .execute(pool)
+                    .await?;
+            }
+            WebhookResponse::Error { message, code } => {
+                warn!("Received error from endpoint: {} code: {}", message, code);
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_service, FakeResponse};
+
+    fn named_mod(id: i64, name: &str) -> Mod {
+        Mod {
+            id,
+            category: Some(0),
+            name: Some(name.to_string()),
+            url: Some(format!("https://mod.io/g/drg/m/{}", id)),
+        }
+    }
+
+    fn field(value: &str) -> WebhookField {
+        WebhookField {
+            name: "name".to_string(),
+            value: value.to_string(),
+            inline: true,
+        }
+    }
+
+    fn body(embeds: Vec<WebhookEmbed>) -> WebhookBody {
+        WebhookBody {
+            avatar_url: None,
+            embeds,
+            ..Default::default()
+        }
+    }
+
+    fn lobby(classes: &str, start: &str, mods: Vec<Mod>) -> Lobby {
+        Lobby {
+            lobby_id: "1".to_string(),
+            diff: 4,
+            region: "Europe".to_string(),
+            host_user_id: "76561198000000001".to_string(),
+            server_name: "Rock and Stone".to_string(),
+            numplayers: 1,
+            classes: classes.to_string(),
+            start: start.to_string(),
+            mods,
+        }
+    }
+
+    #[test]
+    fn counts_classes_skipping_malformed_entries() {
+        let state = LobbyState::of(&lobby("0;3; 3;x;7;;", "", vec![]));
+        assert_eq!(state.classes, [1, 0, 0, 2]);
+        assert_eq!(state.hazard, 5);
+        assert!(!state.in_mission);
+    }
+
+    #[test]
+    fn notes_mission_and_class_changes() {
+        let old = LobbyState::of(&lobby("0;1;", "", vec![]));
+        let new = LobbyState::of(&lobby("0;2;2;", "1700000000", vec![]));
+        assert_eq!(
+            new.changes_since(&old),
+            ["Mission started", "Engineer slot opened", "Gunner joined"]
+        );
+        assert_eq!(
+            old.changes_since(&new),
+            [
+                "Returned to the Space Rig",
+                "Engineer joined",
+                "Gunner slot opened"
+            ]
+        );
+        assert!(new.changes_since(&new).is_empty());
+    }
+
+    #[test]
+    fn notes_added_and_removed_mods() {
+        let hidden = Mod {
+            id: 3,
+            category: Some(0),
+            name: None,
+            url: None,
+        };
+        let old = LobbyState::of(&lobby(
+            "",
+            "",
+            vec![
+                named_mod(1, "Better Scout"),
+                named_mod(2, "Custom Difficulty"),
+            ],
+        ));
+        let new = LobbyState::of(&lobby(
+            "",
+            "",
+            vec![named_mod(2, "Custom Difficulty"), hidden],
+        ));
+        assert_eq!(
+            new.changes_since(&old),
+            ["Mods changed", "+ Hidden mod (3)", "- Better Scout"]
+        );
+    }
+
+    fn total_chars(body: &WebhookBody) -> usize {
+        body.embeds.iter().map(embed_chars).sum()
+    }
+
+    #[test]
+    fn mod_list_shows_all_mods_that_fit() {
+        let hidden = Mod {
+            id: 2,
+            category: Some(0),
+            name: None,
+            url: None,
+        };
+        let a = named_mod(1, "A");
+        assert_eq!(
+            format_mod_list(&[&a, &hidden]),
+            "[A](https://mod.io/g/drg/m/1)\nHidden mod"
+        );
+        assert_eq!(format_mod_list(&[]), "");
+    }
+
+    #[test]
+    fn mod_list_is_cut_off_at_field_limit() {
+        let mods: Vec<Mod> = (0..100).map(|i| named_mod(i, &"x".repeat(40))).collect();
+        let refs: Vec<&Mod> = mods.iter().collect();
+        let value = format_mod_list(&refs);
+
+        assert!(value.chars().count() <= FIELD_VALUE_LIMIT);
+        let shown = value.lines().filter(|l| l.starts_with('[')).count();
+        assert!(shown > 0);
+        assert!(value.ends_with(&format!("...and {} more", 100 - shown)));
+        assert_eq!(value, format_mod_list(&refs));
+    }
+
+    #[test]
+    fn lines_exactly_at_limit_are_not_cut() {
+        let lines = vec!["a".repeat(10), "b".repeat(9)];
+        assert_eq!(
+            join_lines(&lines, 20),
+            format!("{}\n{}", lines[0], lines[1])
+        );
+        assert_eq!(join_lines(&lines, 19), "...and 2 more");
+    }
+
+    #[test]
+    fn overlong_line_is_replaced_by_count() {
+        let lines = vec!["a".repeat(2000)];
+        assert_eq!(join_lines(&lines, FIELD_VALUE_LIMIT), "...and 1 more");
+    }
+
+    #[test]
+    fn text_is_truncated_by_characters() {
+        let embed = WebhookEmbed {
+            title: "é".repeat(300),
+            description: "d".repeat(5000),
+            fields: vec![field(&"ü".repeat(2000))],
+            ..Default::default()
+        };
+        let fitted = body(vec![embed]).fit_limits();
+        let embed = &fitted.embeds[0];
+
+        assert_eq!(embed.title.chars().count(), TITLE_LIMIT);
+        assert!(embed.title.ends_with('…'));
+        assert_eq!(embed.description.chars().count(), DESCRIPTION_LIMIT);
+        assert_eq!(embed.fields[0].value.chars().count(), FIELD_VALUE_LIMIT);
+    }
+
+    #[test]
+    fn small_body_is_unchanged() {
+        let original = body(vec![WebhookEmbed {
+            title: "lobby".to_string(),
+            fields: vec![field("a"), field("b")],
+            color: Some(1),
+            ..Default::default()
+        }]);
+        assert_eq!(original.clone().fit_limits(), original);
+        assert_eq!(body(vec![]).fit_limits(), body(vec![]));
+    }
+
+    #[test]
+    fn extra_fields_are_split_into_embeds() {
+        let fitted = body(vec![WebhookEmbed {
+            title: "lobby".to_string(),
+            fields: (0..30).map(|i| field(&i.to_string())).collect(),
+            color: Some(7),
+            ..Default::default()
+        }])
+        .fit_limits();
+
+        assert_eq!(fitted.embeds.len(), 2);
+        assert_eq!(fitted.embeds[0].fields.len(), FIELD_COUNT_LIMIT);
+        assert_eq!(fitted.embeds[1].fields.len(), 5);
+        assert_eq!(fitted.embeds[1].fields[0].value, "25");
+        assert_eq!(fitted.embeds[1].color, Some(7));
+        assert!(fitted.embeds[1].title.is_empty());
+    }
+
+    #[test]
+    fn fields_over_total_limit_are_replaced_by_count() {
+        let fitted = body(vec![WebhookEmbed {
+            title: "lobby".to_string(),
+            fields: (0..10).map(|_| field(&"v".repeat(1000))).collect(),
+            ..Default::default()
+        }])
+        .fit_limits();
+
+        assert!(total_chars(&fitted) <= TOTAL_LIMIT);
+        let fields = &fitted.embeds[0].fields;
+        let shown = fields.len() - 1;
+        assert_eq!(shown, 5);
+        assert_eq!(
+            fields.last().unwrap().value,
+            format!("...and {} more", 10 - shown)
+        );
+    }
+
+    #[test]
+    fn note_makes_room_in_full_embed() {
+        let fitted = body(
+            (0..12)
+                .map(|i| WebhookEmbed {
+                    title: i.to_string(),
+                    fields: (0..FIELD_COUNT_LIMIT).map(|_| field("v")).collect(),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+        .fit_limits();
+
+        assert_eq!(fitted.embeds.len(), EMBED_COUNT_LIMIT);
+        let last = &fitted.embeds.last().unwrap().fields;
+        assert_eq!(last.len(), FIELD_COUNT_LIMIT);
+        assert_eq!(last.last().unwrap().value, "...and 51 more");
+    }
+
+    #[test]
+    fn descriptions_are_shortened_when_fields_are_gone() {
+        let fitted = body(
+            (0..2)
+                .map(|_| WebhookEmbed {
+                    description: "d".repeat(DESCRIPTION_LIMIT),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+        .fit_limits();
+
+        assert_eq!(fitted.embeds.len(), 2);
+        assert_eq!(total_chars(&fitted), TOTAL_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn forum_posts_open_threads() {
+        let (server, limiter, url) = fake_service(vec![FakeResponse::new(
+            200,
+            r#"{"id":"10","channel_id":"20"}"#,
+        )])
+        .await;
+        let sink = DiscordSink::new(&limiter, url);
+
+        let data = WebhookBody {
+            thread_name: Some("lobby".to_string()),
+            ..body(vec![])
+        };
+        let sent = sink.send(None, None, &data).await.unwrap();
+        assert_eq!(
+            sent,
+            Delivery::Sent {
+                id: "10".to_string(),
+                thread_id: Some("20".to_string())
+            }
+        );
+        sink.send(Some("10"), Some("20"), &body(vec![]))
+            .await
+            .unwrap();
+
+        let received = server.received().await;
+        assert_eq!(received[0].method, "POST");
+        assert_eq!(received[0].path, "/?wait=true");
+        assert_eq!(received[1].method, "PATCH");
+        assert_eq!(received[1].path, "/messages/10?wait=true&thread_id=20");
+    }
+
+    #[tokio::test]
+    async fn unknown_messages_are_gone() {
+        let (_server, limiter, url) = fake_service(vec![FakeResponse::new(
+            404,
+            r#"{"message":"Unknown Message","code":10008}"#,
+        )])
+        .await;
+        let sink = DiscordSink::new(&limiter, url);
+
+        let res = sink.send(Some("10"), None, &body(vec![])).await.unwrap();
+        assert_eq!(res, Delivery::Gone);
+        sink.delete("10", None).await.unwrap();
+    }
+}
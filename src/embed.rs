@@ -0,0 +1,277 @@
+use minijinja::value::ViaDeserialize;
+use minijinja::{context, Environment};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+
+use crate::discord::{format_mod_list, WebhookAuthor, WebhookBody, WebhookEmbed, WebhookField};
+use crate::lobby::{Class, Lobby, Mod};
+use crate::steam::Player;
+
+const AVATAR_URL: &str =
+    "https://cdn.discordapp.com/attachments/878318716801155236/968174640847523930/engo.png";
+
+/// Layout of the embed posted for a lobby. Every string except `avatar_url` is a minijinja template
+/// rendered with these variables:
+///
+/// - `lobby`: `lobby_id`, `server_name`, `region`, `diff`, `hazard`, `numplayers`,
+///   `host_user_id`, `in_mission` and `mods`
+/// - `host`: cached Steam summary of the host with `personaname`, `avatar` and `avatarfull`, or
+///   none if unknown
+/// - `classes`: emoji of each player's class, padded with the empty emoji to four
+/// - `verified_mods`, `approved_mods`, `sandbox_mods`: mods with `id`, `name`, `url` and
+///   `category`
+///
+/// `mod_list(mods)` formats a list of mods as markdown links. Fields whose value renders empty are
+/// left out, as are the color and author if they render empty.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbedTemplate {
+    /// Avatar of the webhook user, Discord's default if empty
+    pub avatar_url: String,
+    pub title: String,
+    pub description: String,
+    /// Either `#rrggbb` or a decimal number
+    pub color: String,
+    pub author_name: String,
+    pub author_icon: String,
+    pub author_url: String,
+    pub fields: Vec<FieldTemplate>,
+    pub emoji: ClassEmoji,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldTemplate {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+/// Emoji shown for each class in the `classes` variable
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassEmoji {
+    pub driller: String,
+    pub engineer: String,
+    pub gunner: String,
+    pub scout: String,
+    /// Fills the slots of missing players
+    pub empty: String,
+}
+
+impl Default for ClassEmoji {
+    fn default() -> Self {
+        Self {
+            driller: "<:driller:964680901621612584>".to_string(),
+            engineer: "<:engineer:964680922920255548>".to_string(),
+            gunner: "<:gunner:964680948530704404>".to_string(),
+            scout: "<:scout:964680965521813524>".to_string(),
+            empty: "<:empty:964681045347823616>".to_string(),
+        }
+    }
+}
+
+impl ClassEmoji {
+    /// Maps the `0;1;3;` class list of a lobby to emoji
+    pub fn format<'a>(&'a self, classes: &str) -> Vec<&'a str> {
+        let mut emoji: Vec<&str> = classes
+            .split(';')
+            .filter(|c| !c.is_empty())
+            .map(|c| match Class::parse(c) {
+                Some(Class::Driller) => self.driller.as_str(),
+                Some(Class::Engineer) => self.engineer.as_str(),
+                Some(Class::Gunner) => self.gunner.as_str(),
+                Some(Class::Scout) => self.scout.as_str(),
+                None => "<unknown>",
+            })
+            .collect();
+        while emoji.len() < 4 {
+            emoji.push(&self.empty);
+        }
+        emoji
+    }
+}
+
+impl Default for EmbedTemplate {
+    fn default() -> Self {
+        fn field(name: &str, value: &str, inline: bool) -> FieldTemplate {
+            FieldTemplate {
+                name: name.to_string(),
+                value: value.to_string(),
+                inline,
+            }
+        }
+
+        Self {
+            avatar_url: AVATAR_URL.to_string(),
+            title: "{{ lobby.server_name }}".to_string(),
+            description: "steam://joinlobby/548430/{{ lobby.lobby_id }}/{{ lobby.host_user_id }}"
+                .to_string(),
+            color: String::new(),
+            author_name: "{{ host.personaname if host else 'Unknown host' }}".to_string(),
+            author_icon: "{{ host.avatarfull if host }}".to_string(),
+            author_url: "https://steamcommunity.com/profiles/{{ lobby.host_user_id }}".to_string(),
+            fields: vec![
+                field("Region", "{{ lobby.region }}", true),
+                field("Difficulty", "Hazard {{ lobby.hazard }}", true),
+                field("Classes", "{{ classes | join('') }}", true),
+                field(
+                    "Status",
+                    "{% if lobby.in_mission %}In Mission{% else %}In Space Rig{% endif %}",
+                    false,
+                ),
+                field("Verified Mods", "{{ mod_list(verified_mods) }}", true),
+                field("Approved Mods", "{{ mod_list(approved_mods) }}", true),
+                field("Sandboxed Mods", "{{ mod_list(sandbox_mods) }}", true),
+            ],
+            emoji: ClassEmoji::default(),
+        }
+    }
+}
+
+/// Lobby as exposed to templates
+#[derive(Serialize)]
+struct TemplateLobby<'a> {
+    lobby_id: &'a str,
+    server_name: &'a str,
+    region: &'a str,
+    diff: i64,
+    hazard: i64,
+    numplayers: i64,
+    host_user_id: &'a str,
+    in_mission: bool,
+    mods: &'a [Mod],
+}
+
+fn environment<'source>() -> Environment<'source> {
+    let mut env = Environment::new();
+    env.add_function("mod_list", |mods: ViaDeserialize<Vec<Mod>>| {
+        format_mod_list(&mods.iter().collect_vec())
+    });
+    env
+}
+
+fn non_empty(s: String) -> Option<String> {
+    if s.trim().is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn parse_color(color: &str) -> Result<Option<u32>> {
+    let color = color.trim();
+    if color.is_empty() {
+        return Ok(None);
+    }
+    let parsed = match color.strip_prefix('#') {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => color.parse(),
+    };
+    Ok(Some(parsed.with_context(|| {
+        format!("invalid embed color {:?}", color)
+    })?))
+}
+
+impl EmbedTemplate {
+    fn sources(&self) -> impl Iterator<Item = &str> {
+        [
+            &self.title,
+            &self.description,
+            &self.color,
+            &self.author_name,
+            &self.author_icon,
+            &self.author_url,
+        ]
+        .into_iter()
+        .chain(self.fields.iter().flat_map(|f| [&f.name, &f.value]))
+        .map(String::as_str)
+    }
+
+    /// Checks that every template compiles
+    pub fn validate(&self) -> Result<()> {
+        let env = environment();
+        for source in self.sources() {
+            env.template_from_str(source)
+                .with_context(|| format!("invalid template {:?}", source))?;
+        }
+        Ok(())
+    }
+
+    pub fn avatar_url(&self) -> Option<String> {
+        non_empty(self.avatar_url.clone())
+    }
+
+    pub fn render(&self, lobby: &Lobby, host: Option<&Player>) -> Result<WebhookBody> {
+        let env = environment();
+        let by_category = |category| {
+            lobby
+                .mods
+                .iter()
+                .filter(|m| m.category == Some(category))
+                .collect_vec()
+        };
+        let ctx = context! {
+            lobby => TemplateLobby {
+                lobby_id: &lobby.lobby_id,
+                server_name: &lobby.server_name,
+                region: &lobby.region,
+                diff: lobby.diff,
+                hazard: lobby.hazard(),
+                numplayers: lobby.numplayers,
+                host_user_id: &lobby.host_user_id,
+                in_mission: lobby.in_mission(),
+                mods: &lobby.mods,
+            },
+            host => host,
+            classes => self.emoji.format(&lobby.classes),
+            verified_mods => by_category(0),
+            approved_mods => by_category(1),
+            sandbox_mods => by_category(2),
+        };
+        let render = |source: &str| {
+            env.render_str(source, &ctx)
+                .with_context(|| format!("failed to render template {:?}", source))
+        };
+
+        let mut fields = vec![];
+        for field in &self.fields {
+            let value = render(&field.value)?;
+            if value.trim().is_empty() {
+                continue;
+            }
+            fields.push(WebhookField {
+                name: render(&field.name)?,
+                value,
+                inline: field.inline,
+            });
+        }
+
+        let author = non_empty(render(&self.author_name)?)
+            .map(|name| -> Result<_> {
+                Ok(WebhookAuthor {
+                    name,
+                    icon_url: non_empty(render(&self.author_icon)?),
+                    url: non_empty(render(&self.author_url)?),
+                })
+            })
+            .transpose()?;
+
+        Ok(WebhookBody {
+            avatar_url: self.avatar_url(),
+            embeds: vec![WebhookEmbed {
+                title: render(&self.title)?,
+                author,
+                description: render(&self.description)?,
+                fields,
+                color: parse_color(&render(&self.color)?)?,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .fit_limits())
+    }
+}
@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::Result;
+use tracing::info;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Number of events returned by one call to [`events_since`]
+const PAGE_SIZE: i64 = 500;
+/// Polls in a row a lobby has to be missing from before it counts as closed, so a missed poll or
+/// a partial server list does not close and reopen every lobby
+const CLOSED_AFTER_POLLS: i64 = 2;
+
+/// What changed about a lobby since it was last seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    LobbyOpened,
+    LobbyClosed,
+    MissionStarted,
+    ReturnedToRig,
+    PlayerCountChanged,
+    ModsChanged,
+    Renamed,
+    BecameFull,
+    SlotOpened,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::LobbyOpened => "lobby_opened",
+            Self::LobbyClosed => "lobby_closed",
+            Self::MissionStarted => "mission_started",
+            Self::ReturnedToRig => "returned_to_rig",
+            Self::PlayerCountChanged => "player_count_changed",
+            Self::ModsChanged => "mods_changed",
+            Self::Renamed => "renamed",
+            Self::BecameFull => "became_full",
+            Self::SlotOpened => "slot_opened",
+        }
+    }
+}
+
+/// An event as stored in the `event` table. `data` holds the details of the change, e.g. the old
+/// and new player count.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub event_id: i64,
+    pub time: i64,
+    pub lobby_id: String,
+    pub kind: String,
+    pub data: Value,
+}
+
+/// A change found by [`diff`], not yet stored
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    lobby_id: String,
+    kind: EventKind,
+    data: Value,
+}
+
+/// The parts of a lobby events are derived from
+#[derive(Debug, Clone, PartialEq)]
+struct Snapshot {
+    server_name: String,
+    in_mission: bool,
+    numplayers: i64,
+    full: bool,
+    mods: BTreeSet<i64>,
+}
+
+async fn load_snapshot(pool: &SqlitePool, time: i64) -> Result<BTreeMap<String, Snapshot>> {
+    let res = sqlx::query!(
+        r#"SELECT lobby_id,
+            server_name,
+            start,
+            numplayers,
+            full,
+            (SELECT json_group_array(mod_id)
+                FROM server_mod
+                WHERE server_mod.time = server.time AND server_mod.lobby_id = server.lobby_id
+            ) AS "mods!: String"
+            FROM server
+            WHERE time = ?
+        "#,
+        time,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    res.into_iter()
+        .map(|r| {
+            Ok((
+                r.lobby_id,
+                Snapshot {
+                    server_name: r.server_name,
+                    in_mission: !r.start.is_empty(),
+                    numplayers: r.numplayers,
+                    full: r.full != 0,
+                    mods: serde_json::from_str(&r.mods)?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Changes from each lobby's latest `previous` snapshot to `current`. Lobbies missing from
+/// `current` only count as closed if they are `expiring`, i.e. were missing long enough.
+fn diff(
+    previous: &BTreeMap<String, Snapshot>,
+    current: &BTreeMap<String, Snapshot>,
+    expiring: &BTreeSet<String>,
+) -> Vec<Change> {
+    let mut changes = vec![];
+    let mut push = |lobby_id: &str, kind, data| {
+        changes.push(Change {
+            lobby_id: lobby_id.to_string(),
+            kind,
+            data,
+        })
+    };
+
+    for lobby_id in expiring {
+        if !current.contains_key(lobby_id) {
+            push(lobby_id, EventKind::LobbyClosed, json!({}));
+        }
+    }
+    for (lobby_id, new) in current {
+        let Some(old) = previous.get(lobby_id) else {
+            push(
+                lobby_id,
+                EventKind::LobbyOpened,
+                json!({ "server_name": new.server_name, "numplayers": new.numplayers }),
+            );
+            continue;
+        };
+
+        if old.server_name != new.server_name {
+            push(
+                lobby_id,
+                EventKind::Renamed,
+                json!({ "from": old.server_name, "to": new.server_name }),
+            );
+        }
+        if !old.in_mission && new.in_mission {
+            push(lobby_id, EventKind::MissionStarted, json!({}));
+        } else if old.in_mission && !new.in_mission {
+            push(lobby_id, EventKind::ReturnedToRig, json!({}));
+        }
+        if old.numplayers != new.numplayers {
+            push(
+                lobby_id,
+                EventKind::PlayerCountChanged,
+                json!({ "from": old.numplayers, "to": new.numplayers }),
+            );
+        }
+        if !old.full && new.full {
+            push(
+                lobby_id,
+                EventKind::BecameFull,
+                json!({ "numplayers": new.numplayers }),
+            );
+        } else if old.full && !new.full {
+            push(
+                lobby_id,
+                EventKind::SlotOpened,
+                json!({ "numplayers": new.numplayers }),
+            );
+        }
+        if old.mods != new.mods {
+            let added: Vec<i64> = new.mods.difference(&old.mods).copied().collect();
+            let removed: Vec<i64> = old.mods.difference(&new.mods).copied().collect();
+            push(
+                lobby_id,
+                EventKind::ModsChanged,
+                json!({ "added": added, "removed": removed }),
+            );
+        }
+    }
+    changes
+}
+
+/// Compares the snapshot at `time` against each lobby's latest snapshot within the
+/// [`CLOSED_AFTER_POLLS`] snapshots before it and records what changed. Lobbies missing from all
+/// of them count as opened, lobbies last seen in the oldest of them as closed.
+#[tracing::instrument(skip(pool))]
+pub async fn record_events(pool: &SqlitePool, time: i64) -> Result<()> {
+    let times = sqlx::query_scalar!(
+        "SELECT DISTINCT time FROM server WHERE time < ? ORDER BY time DESC LIMIT ?",
+        time,
+        CLOSED_AFTER_POLLS,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // oldest first, so later snapshots of a lobby replace earlier ones
+    let mut last_seen = BTreeMap::new();
+    for &previous_time in times.iter().rev() {
+        for (lobby_id, snapshot) in load_snapshot(pool, previous_time).await? {
+            last_seen.insert(lobby_id, (previous_time, snapshot));
+        }
+    }
+    let oldest = times.last().copied();
+    let window_full = times.len() as i64 == CLOSED_AFTER_POLLS;
+    let expiring: BTreeSet<String> = last_seen
+        .iter()
+        .filter(|(_, (seen, _))| window_full && Some(*seen) == oldest)
+        .map(|(lobby_id, _)| lobby_id.clone())
+        .collect();
+    let previous: BTreeMap<String, Snapshot> = last_seen
+        .into_iter()
+        .map(|(lobby_id, (_, snapshot))| (lobby_id, snapshot))
+        .collect();
+    let current = load_snapshot(pool, time).await?;
+
+    let changes = diff(&previous, &current, &expiring);
+    info!("recording {} lobby events", changes.len());
+    let mut tx = pool.begin().await?;
+    for change in changes {
+        let kind = change.kind.as_str();
+        let data = change.data.to_string();
+        sqlx::query!(
+            "INSERT INTO event (time, lobby_id, kind, data) VALUES (?, ?, ?, ?)",
+            time,
+            change.lobby_id,
+            kind,
+            data,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// ID of the latest event, 0 before the first one
+pub async fn latest_event_id(pool: &SqlitePool) -> Result<i64> {
+    Ok(
+        sqlx::query_scalar!(r#"SELECT IFNULL(MAX(event_id), 0) AS "id!: i64" FROM event"#)
+            .fetch_one(pool)
+            .await?,
+    )
+}
+
+/// Events after `after` in the order they were recorded, optionally only those of `lobby_id`.
+/// Events of lobbies whose host opted out are left out.
+pub async fn events_since(
+    pool: &SqlitePool,
+    after: i64,
+    lobby_id: Option<&str>,
+) -> Result<Vec<Event>> {
+    let res = sqlx::query!(
+        r#"SELECT event_id, time, lobby_id, kind, data
+            FROM event
+            WHERE
+                event_id > ?
+                AND (? IS NULL OR lobby_id = ?)
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM server
+                    JOIN host_optout USING(host_user_id)
+                    WHERE server.lobby_id = event.lobby_id
+                )
+            ORDER BY event_id
+            LIMIT ?
+        "#,
+        after,
+        lobby_id,
+        lobby_id,
+        PAGE_SIZE,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    res.into_iter()
+        .map(|r| {
+            Ok(Event {
+                event_id: r.event_id,
+                time: r.time,
+                lobby_id: r.lobby_id,
+                kind: r.kind,
+                data: serde_json::from_str(&r.data)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(numplayers: i64, mods: &[i64]) -> Snapshot {
+        Snapshot {
+            server_name: "Rock and Stone".to_string(),
+            in_mission: false,
+            numplayers,
+            full: numplayers == 4,
+            mods: mods.iter().copied().collect(),
+        }
+    }
+
+    fn kinds(changes: &[Change]) -> Vec<(&str, EventKind)> {
+        changes
+            .iter()
+            .map(|c| (c.lobby_id.as_str(), c.kind))
+            .collect()
+    }
+
+    fn expiring(lobby_ids: &[&str]) -> BTreeSet<String> {
+        lobby_ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn opened_and_closed() {
+        let previous = BTreeMap::from([("a".to_string(), snapshot(1, &[]))]);
+        let current = BTreeMap::from([("b".to_string(), snapshot(2, &[]))]);
+
+        let changes = diff(&previous, &current, &expiring(&["a"]));
+        assert_eq!(
+            kinds(&changes),
+            [("a", EventKind::LobbyClosed), ("b", EventKind::LobbyOpened)]
+        );
+        assert_eq!(changes[1].data["numplayers"], 2);
+    }
+
+    #[test]
+    fn changes_of_one_lobby() {
+        let previous = BTreeMap::from([("a".to_string(), snapshot(3, &[1, 2]))]);
+        let current = BTreeMap::from([(
+            "a".to_string(),
+            Snapshot {
+                server_name: "Karl's lobby".to_string(),
+                in_mission: true,
+                ..snapshot(4, &[2, 3])
+            },
+        )]);
+
+        let changes = diff(&previous, &current, &expiring(&[]));
+        assert_eq!(
+            kinds(&changes),
+            [
+                ("a", EventKind::Renamed),
+                ("a", EventKind::MissionStarted),
+                ("a", EventKind::PlayerCountChanged),
+                ("a", EventKind::BecameFull),
+                ("a", EventKind::ModsChanged),
+            ]
+        );
+        assert_eq!(changes[2].data, json!({ "from": 3, "to": 4 }));
+        assert_eq!(changes[4].data, json!({ "added": [3], "removed": [1] }));
+    }
+
+    #[test]
+    fn slot_opened_in_full_lobby() {
+        let previous = BTreeMap::from([("a".to_string(), snapshot(4, &[]))]);
+        let current = BTreeMap::from([("a".to_string(), snapshot(3, &[]))]);
+
+        let changes = diff(&previous, &current, &expiring(&[]));
+        assert_eq!(
+            kinds(&changes),
+            [
+                ("a", EventKind::PlayerCountChanged),
+                ("a", EventKind::SlotOpened)
+            ]
+        );
+        assert_eq!(changes[1].data["numplayers"], 3);
+    }
+
+    #[test]
+    fn unchanged_lobbies_have_no_events() {
+        let previous = BTreeMap::from([("a".to_string(), snapshot(2, &[1]))]);
+        assert!(diff(&previous, &previous.clone(), &expiring(&["a"])).is_empty());
+    }
+
+    #[test]
+    fn lobbies_missing_briefly_stay_open() {
+        let previous = BTreeMap::from([
+            ("a".to_string(), snapshot(1, &[])),
+            ("b".to_string(), snapshot(2, &[])),
+        ]);
+        let current = BTreeMap::from([("b".to_string(), snapshot(2, &[]))]);
+        assert!(diff(&previous, &current, &expiring(&["b"])).is_empty());
+    }
+
+    async fn add_lobby(pool: &SqlitePool, time: i64, lobby_id: &str) {
+        sqlx::query!(
+            r#"INSERT INTO server (time, lobby_id, host_user_id, server_name, server_name_san,
+                    global_mission_seed, mission_seed, diff, gamestate, numplayers, full, region,
+                    start, classes, classlock, mission_structure, password, p2paddress, p2pport,
+                    distance)
+                VALUES (?, ?, '1', 'Rock and Stone', 'Rock and Stone', '', '', 1, 0, 1, 0,
+                    'Europe', '', '', 0, '', 0, '', 0, 0)
+            "#,
+            time,
+            lobby_id,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn recorded(pool: &SqlitePool, time: i64) -> Vec<(String, String)> {
+        sqlx::query!(
+            "SELECT lobby_id, kind FROM event WHERE time = ? ORDER BY event_id",
+            time
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.lobby_id, r.kind))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn closes_lobbies_after_missed_polls() {
+        let pool = crate::testutil::memory_pool().await;
+        // "b" is missing from the poll at 2, "a" from every poll after 1
+        for (time, lobbies) in [
+            (1, &["a", "b"][..]),
+            (2, &["c"]),
+            (3, &["b", "c"]),
+            (4, &["b", "c"]),
+        ] {
+            for lobby_id in lobbies {
+                add_lobby(&pool, time, lobby_id).await;
+            }
+            record_events(&pool, time).await.unwrap();
+        }
+
+        let event = |lobby_id: &str, kind: &str| (lobby_id.to_string(), kind.to_string());
+        assert_eq!(recorded(&pool, 2).await, [event("c", "lobby_opened")]);
+        assert_eq!(recorded(&pool, 3).await, [event("a", "lobby_closed")]);
+        assert!(recorded(&pool, 4).await.is_empty());
+    }
+}
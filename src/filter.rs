@@ -0,0 +1,182 @@
+use serde::{Deserialize, Deserializer};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::lobby::Lobby;
+
+/// Rules deciding which lobbies are posted to Discord. Every rule that is set must pass for a
+/// lobby to match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LobbyFilter {
+    /// Mods that must all be present
+    #[serde(default)]
+    pub required_mods: Vec<i64>,
+    /// Mods that must not be present
+    #[serde(default)]
+    pub excluded_mods: Vec<i64>,
+    /// Lowest hazard level, inclusive
+    pub min_hazard: Option<i64>,
+    /// Highest hazard level, inclusive
+    pub max_hazard: Option<i64>,
+    /// Regions the lobby must be in, empty allows all
+    #[serde(default)]
+    pub regions: Vec<String>,
+    /// Case insensitive patterns of which at least one must match the lobby name
+    #[serde(default)]
+    pub name_include: Vec<Pattern>,
+    /// Case insensitive patterns of which none may match the lobby name
+    #[serde(default)]
+    pub name_exclude: Vec<Pattern>,
+    /// Only lobbies seen within this many minutes are considered
+    #[serde(default = "default_window_minutes")]
+    pub window_minutes: i64,
+}
+
+fn default_window_minutes() -> i64 {
+    10
+}
+
+impl Default for LobbyFilter {
+    fn default() -> Self {
+        Self {
+            required_mods: vec![],
+            excluded_mods: vec![],
+            min_hazard: None,
+            max_hazard: None,
+            regions: vec![],
+            name_include: vec![],
+            name_exclude: vec![],
+            window_minutes: default_window_minutes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl Pattern {
+    /// Pattern matching names that contain `text`, ignoring case
+    pub fn containing(text: &str) -> Self {
+        let regex = RegexBuilder::new(&regex::escape(text))
+            .case_insensitive(true)
+            .build()
+            .expect("escaped text is a valid pattern");
+        Pattern(regex)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Outcome of a single rule
+#[derive(Debug)]
+pub struct Check {
+    pub passed: bool,
+    pub description: String,
+}
+
+/// Outcome of evaluating every rule of a filter against a lobby
+#[derive(Debug)]
+pub struct Verdict {
+    pub checks: Vec<Check>,
+}
+
+impl Verdict {
+    pub fn matched(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+}
+
+impl LobbyFilter {
+    pub fn matches(&self, lobby: &Lobby) -> bool {
+        self.evaluate(lobby).matched()
+    }
+
+    /// Evaluates every rule, recording why each passed or failed
+    pub fn evaluate(&self, lobby: &Lobby) -> Verdict {
+        let mut checks = vec![];
+        let mut check = |passed: bool, description: String| {
+            checks.push(Check {
+                passed,
+                description,
+            })
+        };
+
+        for id in &self.required_mods {
+            let present = lobby.has_mod(*id);
+            check(
+                present,
+                format!(
+                    "required mod {} is {}",
+                    id,
+                    if present { "present" } else { "missing" }
+                ),
+            );
+        }
+        for id in &self.excluded_mods {
+            let present = lobby.has_mod(*id);
+            check(
+                !present,
+                format!(
+                    "excluded mod {} is {}",
+                    id,
+                    if present { "present" } else { "absent" }
+                ),
+            );
+        }
+        if let Some(min) = self.min_hazard {
+            check(
+                lobby.hazard() >= min,
+                format!("hazard {} >= {}", lobby.hazard(), min),
+            );
+        }
+        if let Some(max) = self.max_hazard {
+            check(
+                lobby.hazard() <= max,
+                format!("hazard {} <= {}", lobby.hazard(), max),
+            );
+        }
+        if !self.regions.is_empty() {
+            check(
+                self.regions.iter().any(|r| r == &lobby.region),
+                format!("region {:?} in {:?}", lobby.region, self.regions),
+            );
+        }
+        if !self.name_include.is_empty() {
+            let matched = self
+                .name_include
+                .iter()
+                .find(|p| p.0.is_match(&lobby.server_name));
+            check(
+                matched.is_some(),
+                match matched {
+                    Some(p) => format!("name matches include pattern {:?}", p.0.as_str()),
+                    None => "name matches no include pattern".to_string(),
+                },
+            );
+        }
+        let excluded = self
+            .name_exclude
+            .iter()
+            .find(|p| p.0.is_match(&lobby.server_name));
+        if let Some(p) = excluded {
+            check(
+                false,
+                format!("name matches exclude pattern {:?}", p.0.as_str()),
+            );
+        } else if !self.name_exclude.is_empty() {
+            check(true, "name matches no exclude pattern".to_string());
+        }
+
+        Verdict { checks }
+    }
+}
@@ -0,0 +1,555 @@
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::info;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::discord::{join_lines, WebhookBody, WebhookEmbed, WebhookField, WebhookImage};
+use crate::embed::EmbedTemplate;
+use crate::lobby::{category_name, latest_lobbies, Lobby};
+use crate::moderation::Moderation;
+use crate::steam::cached_players;
+use crate::watch::add_watch;
+
+const PING: u8 = 1;
+const APPLICATION_COMMAND: u8 = 2;
+
+const PONG: u8 = 1;
+const CHANNEL_MESSAGE_WITH_SOURCE: u8 = 4;
+
+/// Message flag making a response visible only to the user who ran the command
+const EPHEMERAL: u32 = 1 << 6;
+
+/// Lobbies seen within this many minutes are listed by the commands
+const WINDOW_MINUTES: i64 = 10;
+
+/// Requests signed further than this from the current time are rejected, so captured requests
+/// cannot be replayed later
+const MAX_TIMESTAMP_SKEW_SECONDS: i64 = 5 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct Interaction {
+    #[serde(rename = "type")]
+    kind: u8,
+    data: Option<CommandData>,
+    /// Set for commands run in a server
+    member: Option<Member>,
+    /// Set for commands run in a DM
+    user: Option<User>,
+}
+
+impl Interaction {
+    fn user_id(&self) -> Option<&str> {
+        self.member
+            .as_ref()
+            .map(|m| &m.user)
+            .or(self.user.as_ref())
+            .map(|u| u.id.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Member {
+    user: User,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandData {
+    name: String,
+    #[serde(default)]
+    options: Vec<CommandOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandOption {
+    name: String,
+    value: serde_json::Value,
+}
+
+impl CommandData {
+    fn string_option(&self, name: &str) -> Result<&str> {
+        self.options
+            .iter()
+            .find(|o| o.name == name)
+            .and_then(|o| o.value.as_str())
+            .with_context(|| format!("/{} is missing option {:?}", self.name, name))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InteractionResponse {
+    #[serde(rename = "type")]
+    kind: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<ResponseData>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseData {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    content: String,
+    embeds: Vec<WebhookEmbed>,
+    flags: u32,
+}
+
+impl InteractionResponse {
+    fn embeds(embeds: Vec<WebhookEmbed>) -> Self {
+        let body = WebhookBody {
+            avatar_url: None,
+            embeds,
+            ..Default::default()
+        }
+        .fit_limits();
+        Self {
+            kind: CHANNEL_MESSAGE_WITH_SOURCE,
+            data: Some(ResponseData {
+                content: String::new(),
+                embeds: body.embeds,
+                flags: 0,
+            }),
+        }
+    }
+
+    /// Plain text only shown to the user who ran the command
+    pub fn ephemeral(content: impl Into<String>) -> Self {
+        Self {
+            kind: CHANNEL_MESSAGE_WITH_SOURCE,
+            data: Some(ResponseData {
+                content: content.into(),
+                embeds: vec![],
+                flags: EPHEMERAL,
+            }),
+        }
+    }
+}
+
+/// Parses the hex encoded public key of the Discord application
+pub fn parse_public_key(hex_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key.trim())?
+        .try_into()
+        .map_err(|_| anyhow!("public key must be 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Checks the `X-Signature-Ed25519` header Discord sends over the `X-Signature-Timestamp` header
+/// followed by the body, and that the timestamp is within [`MAX_TIMESTAMP_SKEW_SECONDS`] of `now`
+pub fn verify_request(
+    key: &VerifyingKey,
+    signature: &str,
+    timestamp: &str,
+    body: &[u8],
+    now: i64,
+) -> Result<()> {
+    let signed_at: i64 = timestamp.parse().context("timestamp must be a Unix time")?;
+    if (now - signed_at).abs() > MAX_TIMESTAMP_SKEW_SECONDS {
+        bail!("timestamp {} is too far from now", signed_at);
+    }
+    let signature: [u8; 64] = hex::decode(signature)?
+        .try_into()
+        .map_err(|_| anyhow!("signature must be 64 bytes"))?;
+    let message = [timestamp.as_bytes(), body].concat();
+    key.verify(&message, &Signature::from_bytes(&signature))?;
+    Ok(())
+}
+
+/// Answers a verified interaction. `/lobby` renders the lobby with `embed`.
+pub async fn respond(
+    pool: &SqlitePool,
+    embed: &EmbedTemplate,
+    interaction: &Interaction,
+) -> Result<InteractionResponse> {
+    match interaction.kind {
+        PING => Ok(InteractionResponse {
+            kind: PONG,
+            data: None,
+        }),
+        APPLICATION_COMMAND => {
+            let data = interaction.data.as_ref().context("command without data")?;
+            info!("running /{}", data.name);
+            match data.name.as_str() {
+                "lobbies" => lobbies(pool).await,
+                "lobby" => lobby(pool, embed, data.string_option("name")?).await,
+                "mod" => mod_info(pool, data.string_option("name")?).await,
+                "stats" => stats(pool).await,
+                "watch" => {
+                    let user_id = interaction.user_id().context("command without user")?;
+                    watch(pool, user_id, data.string_option("name")?).await
+                }
+                other => Ok(InteractionResponse::ephemeral(format!(
+                    "Unknown command /{}",
+                    other
+                ))),
+            }
+        }
+        other => bail!("unsupported interaction type {}", other),
+    }
+}
+
+/// Lobbies seen within [`WINDOW_MINUTES`] that are not hidden by moderation rules
+async fn visible_lobbies(pool: &SqlitePool) -> Result<Vec<Lobby>> {
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+    let moderation = Moderation::load(pool, now).await?;
+    let mut lobbies = latest_lobbies(pool, WINDOW_MINUTES).await?;
+    lobbies.retain(|l| !moderation.hides(&l.host_user_id, &l.lobby_id, &l.server_name));
+    Ok(lobbies)
+}
+
+async fn lobbies(pool: &SqlitePool) -> Result<InteractionResponse> {
+    let lobbies = visible_lobbies(pool).await?;
+    if lobbies.is_empty() {
+        return Ok(InteractionResponse::ephemeral("No lobbies right now"));
+    }
+
+    let lines: Vec<String> = lobbies
+        .iter()
+        .map(|l| {
+            format!(
+                "**{}** · Hazard {} · {} · {}/4 · {}",
+                l.server_name,
+                l.hazard(),
+                l.region,
+                l.numplayers,
+                if l.in_mission() {
+                    "In Mission"
+                } else {
+                    "In Space Rig"
+                }
+            )
+        })
+        .collect();
+
+    Ok(InteractionResponse::embeds(vec![WebhookEmbed {
+        title: format!("{} lobbies", lobbies.len()),
+        description: join_lines(&lines, 4096),
+        ..Default::default()
+    }]))
+}
+
+/// Current lobby whose name contains `name`, ignoring case
+async fn find_lobby(pool: &SqlitePool, name: &str) -> Result<Option<Lobby>> {
+    let query = name.to_lowercase();
+    Ok(visible_lobbies(pool)
+        .await?
+        .into_iter()
+        .find(|l| l.server_name.to_lowercase().contains(&query)))
+}
+
+async fn lobby(
+    pool: &SqlitePool,
+    embed: &EmbedTemplate,
+    name: &str,
+) -> Result<InteractionResponse> {
+    let Some(lobby) = find_lobby(pool, name).await? else {
+        return Ok(InteractionResponse::ephemeral(format!(
+            "No lobby matching {:?}",
+            name
+        )));
+    };
+
+    let mut hosts = cached_players(pool, &[lobby.host_user_id.as_str()]).await?;
+    let body = embed.render(&lobby, hosts.remove(&lobby.host_user_id).as_ref())?;
+    Ok(InteractionResponse::embeds(body.embeds))
+}
+
+async fn watch(pool: &SqlitePool, user_id: &str, name: &str) -> Result<InteractionResponse> {
+    let Some(lobby) = find_lobby(pool, name).await? else {
+        return Ok(InteractionResponse::ephemeral(format!(
+            "No lobby matching {:?}",
+            name
+        )));
+    };
+
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+    add_watch(pool, &lobby.lobby_id, user_id, now).await?;
+    Ok(InteractionResponse::ephemeral(format!(
+        "Watching **{}**. You will get a DM when a slot opens or it returns to the Space Rig.",
+        lobby.server_name
+    )))
+}
+
+/// `LIKE` pattern matching text that contains `text`, with wildcards escaped by a backslash
+fn contains_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+async fn mod_info(pool: &SqlitePool, name: &str) -> Result<InteractionResponse> {
+    let pattern = contains_pattern(name);
+    let m = sqlx::query!(
+        r#"SELECT mod_id,
+            name,
+            url,
+            json_extract(metadata, '$.summary') AS "summary?: String",
+            json_extract(metadata, '$.logo.thumb_320x180') AS "logo?: String",
+            current_category,
+            first_seen,
+            (SELECT COUNT(*)
+                FROM server_mod
+                WHERE server_mod.mod_id = mod.mod_id AND time = (SELECT MAX(time) FROM server)
+            ) AS "lobbies!: i64"
+            FROM mod
+            WHERE name LIKE ? ESCAPE '\'
+            ORDER BY lower(name) = lower(?) DESC, length(name)
+            LIMIT 1
+        "#,
+        pattern,
+        name,
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(m) = m else {
+        return Ok(InteractionResponse::ephemeral(format!(
+            "No mod matching {:?}",
+            name
+        )));
+    };
+
+    let mut fields = vec![WebhookField {
+        name: "Current lobbies".to_string(),
+        value: m.lobbies.to_string(),
+        inline: true,
+    }];
+    if let Some(category) = m.current_category {
+        fields.push(WebhookField {
+            name: "Category".to_string(),
+            value: category_name(category).to_string(),
+            inline: true,
+        });
+    }
+    if let Some(first_seen) = m.first_seen {
+        fields.push(WebhookField {
+            name: "First seen".to_string(),
+            value: format!("<t:{}:R>", first_seen),
+            inline: true,
+        });
+    }
+
+    Ok(InteractionResponse::embeds(vec![WebhookEmbed {
+        title: m
+            .name
+            .unwrap_or_else(|| format!("Hidden mod ({})", m.mod_id)),
+        url: m.url,
+        description: m.summary.unwrap_or_default(),
+        fields,
+        thumbnail: m.logo.map(|url| WebhookImage { url }),
+        ..Default::default()
+    }]))
+}
+
+async fn stats(pool: &SqlitePool) -> Result<InteractionResponse> {
+    let totals = sqlx::query!(
+        r#"SELECT COUNT(*) AS "lobbies!: i64",
+            COALESCE(SUM(numplayers), 0) AS "players!: i64",
+            COALESCE(SUM(EXISTS(
+                SELECT 1
+                FROM server_mod
+                WHERE server_mod.time = server.time AND server_mod.lobby_id = server.lobby_id
+            )), 0) AS "modded!: i64",
+            (SELECT COUNT(DISTINCT mod_id)
+                FROM server_mod
+                WHERE time = (SELECT MAX(time) FROM server)
+            ) AS "mods!: i64"
+            FROM server
+            WHERE time = (SELECT MAX(time) FROM server)
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let top = sqlx::query!(
+        r#"SELECT mod_id, name, COUNT(*) AS "lobbies!: i64"
+            FROM server_mod
+            JOIN mod USING(mod_id)
+            WHERE time = (SELECT MAX(time) FROM server)
+            GROUP BY mod_id
+            ORDER BY COUNT(*) DESC, mod_id
+            LIMIT 5
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let top: Vec<String> = top
+        .into_iter()
+        .map(|m| {
+            format!(
+                "{} ({})",
+                m.name
+                    .unwrap_or_else(|| format!("Hidden mod ({})", m.mod_id)),
+                m.lobbies
+            )
+        })
+        .collect();
+
+    let field = |name: &str, value: String| WebhookField {
+        name: name.to_string(),
+        value,
+        inline: true,
+    };
+    let mut fields = vec![
+        field("Lobbies", totals.lobbies.to_string()),
+        field("Players", totals.players.to_string()),
+        field("Modded lobbies", totals.modded.to_string()),
+        field("Mods in use", totals.mods.to_string()),
+    ];
+    if !top.is_empty() {
+        fields.push(WebhookField {
+            name: "Most used mods".to_string(),
+            value: join_lines(&top, 1024),
+            inline: false,
+        });
+    }
+
+    Ok(InteractionResponse::embeds(vec![WebhookEmbed {
+        title: "Current lobbies".to_string(),
+        fields,
+        ..Default::default()
+    }]))
+}
+
+/// Registers the slash commands with Discord using `DISCORD_APPLICATION_ID` and
+/// `DISCORD_BOT_TOKEN`
+#[tracing::instrument(skip_all)]
+pub async fn register_commands() -> Result<()> {
+    let application_id = std::env::var("DISCORD_APPLICATION_ID")?;
+    let token = std::env::var("DISCORD_BOT_TOKEN")?;
+
+    let name_option = |description: &str| {
+        serde_json::json!([{
+            "type": 3,
+            "name": "name",
+            "description": description,
+            "required": true,
+        }])
+    };
+    let commands = serde_json::json!([
+        { "name": "lobbies", "description": "List current lobbies" },
+        { "name": "lobby", "description": "Show a lobby", "options": name_option("Part of the lobby name") },
+        { "name": "mod", "description": "Show a mod", "options": name_option("Part of the mod name") },
+        { "name": "stats", "description": "Show lobby and mod statistics" },
+        { "name": "watch", "description": "Get a DM when a slot opens in a full lobby", "options": name_option("Part of the lobby name") },
+    ]);
+
+    let res = reqwest::Client::new()
+        .put(format!(
+            "https://discord.com/api/v10/applications/{}/commands",
+            application_id
+        ))
+        .header("Authorization", format!("Bot {}", token))
+        .json(&commands)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        bail!("failed to register commands: {}", res.text().await?);
+    }
+    info!("registered slash commands");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const NOW: i64 = 1700000000;
+
+    fn sign(key: &SigningKey, timestamp: &str, body: &str) -> String {
+        let message = [timestamp.as_bytes(), body.as_bytes()].concat();
+        hex::encode(key.sign(&message).to_bytes())
+    }
+
+    fn generate_key() -> (SigningKey, VerifyingKey) {
+        let signing = SigningKey::from_bytes(&rand::random());
+        let public = parse_public_key(&hex::encode(signing.verifying_key().to_bytes())).unwrap();
+        (signing, public)
+    }
+
+    #[test]
+    fn accepts_signed_request() {
+        let (signing, public) = generate_key();
+        let signature = sign(&signing, "1700000000", r#"{"type":1}"#);
+        verify_request(&public, &signature, "1700000000", br#"{"type":1}"#, NOW).unwrap();
+    }
+
+    #[test]
+    fn rejects_modified_request() {
+        let (signing, public) = generate_key();
+        let signature = sign(&signing, "1700000000", r#"{"type":1}"#);
+        assert!(verify_request(&public, &signature, "1700000000", br#"{"type":2}"#, NOW).is_err());
+        assert!(verify_request(&public, &signature, "1700000001", br#"{"type":1}"#, NOW).is_err());
+    }
+
+    #[test]
+    fn rejects_stale_request() {
+        let (signing, public) = generate_key();
+        let signature = sign(&signing, "1700000000", r#"{"type":1}"#);
+        let later = NOW + MAX_TIMESTAMP_SKEW_SECONDS + 1;
+        assert!(
+            verify_request(&public, &signature, "1700000000", br#"{"type":1}"#, later).is_err()
+        );
+        let earlier = NOW - MAX_TIMESTAMP_SKEW_SECONDS - 1;
+        assert!(
+            verify_request(&public, &signature, "1700000000", br#"{"type":1}"#, earlier).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_other_key() {
+        let (signing, _) = generate_key();
+        let (_, other) = generate_key();
+        let signature = sign(&signing, "1700000000", r#"{"type":1}"#);
+        assert!(verify_request(&other, &signature, "1700000000", br#"{"type":1}"#, NOW).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let (_, public) = generate_key();
+        assert!(verify_request(&public, "zz", "1700000000", b"{}", NOW).is_err());
+        assert!(verify_request(&public, "abcd", "1700000000", b"{}", NOW).is_err());
+        assert!(verify_request(&public, &"0".repeat(128), "soon", b"{}", NOW).is_err());
+        assert!(parse_public_key("abcd").is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_ping_with_pong() {
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let interaction: Interaction = serde_json::from_str(r#"{"type":1}"#).unwrap();
+        let response = respond(&pool, &EmbedTemplate::default(), &interaction)
+            .await
+            .unwrap();
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"type":1}"#);
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(contains_pattern("100%"), "%100\\%%");
+        assert_eq!(contains_pattern("more_players"), "%more\\_players%");
+        assert_eq!(contains_pattern("a\\b"), "%a\\\\b%");
+    }
+
+    #[test]
+    fn finds_invoking_user() {
+        let in_server: Interaction =
+            serde_json::from_str(r#"{"type":2,"member":{"user":{"id":"1"}}}"#).unwrap();
+        assert_eq!(in_server.user_id(), Some("1"));
+        let in_dm: Interaction = serde_json::from_str(r#"{"type":2,"user":{"id":"2"}}"#).unwrap();
+        assert_eq!(in_dm.user_id(), Some("2"));
+    }
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::Result;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mod {
+    pub id: i64,
+    pub category: Option<i32>,
+    pub name: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Name of a mod.io category of mods
+pub fn category_name(category: i64) -> &'static str {
+    match category {
+        0 => "Verified",
+        1 => "Approved",
+        2 => "Sandbox",
+        _ => "Unknown",
+    }
+}
+
+/// Class of a player, parsed from the `0;1;3;` class list of a lobby
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Driller,
+    Engineer,
+    Gunner,
+    Scout,
+}
+
+impl Class {
+    /// Every class, in the order of their index in class lists
+    pub const ALL: [Class; 4] = [Self::Driller, Self::Engineer, Self::Gunner, Self::Scout];
+
+    /// Parses one entry of a class list
+    pub fn parse(entry: &str) -> Option<Self> {
+        let index: usize = entry.trim().parse().ok()?;
+        Self::ALL.get(index).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Driller => "Driller",
+            Self::Engineer => "Engineer",
+            Self::Gunner => "Gunner",
+            Self::Scout => "Scout",
+        }
+    }
+}
+
+/// Most recent snapshot of a lobby
+#[derive(Debug, Clone)]
+pub struct Lobby {
+    pub lobby_id: String,
+    pub diff: i64,
+    pub region: String,
+    pub host_user_id: String,
+    pub server_name: String,
+    pub numplayers: i64,
+    pub classes: String,
+    pub start: String,
+    pub mods: Vec<Mod>,
+}
+
+impl Lobby {
+    /// Hazard level as displayed in game
+    pub fn hazard(&self) -> i64 {
+        self.diff + 1
+    }
+
+    pub fn in_mission(&self) -> bool {
+        !self.start.is_empty()
+    }
+
+    pub fn has_mod(&self, mod_id: i64) -> bool {
+        self.mods.iter().any(|m| m.id == mod_id)
+    }
+}
+
+/// Returns the most recent snapshot of every lobby seen within the last `window_minutes`
+pub async fn latest_lobbies(pool: &SqlitePool, window_minutes: i64) -> Result<Vec<Lobby>> {
+    let window = window_minutes * 60;
+    let res = sqlx::query!(
+        r#"SELECT time,
+            lobby_id,
+            diff,
+            region,
+            host_user_id,
+            server_name,
+            numplayers,
+            classes,
+            start,
+            (SELECT json_group_array(json_object('id', mod_id, 'category', category, 'name', name, 'url', url)) FROM
+                (SELECT mod_id, server_mod.category, name, url
+                FROM server_mod
+                JOIN mod USING(mod_id)
+                WHERE
+                    server_mod.time = server.time
+                    AND server_mod.lobby_id = server.lobby_id
+                ORDER BY server_mod.category)
+            ) AS "mods?: String"
+            FROM server
+            WHERE (server.time, server.lobby_id) IN (
+                SELECT MAX(time), lobby_id
+                FROM server
+                WHERE time > strftime('%s', 'now') - ?
+                GROUP BY lobby_id
+            )
+            ORDER BY time
+        "#,
+        window,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    res.into_iter()
+        .map(|r| {
+            Ok(Lobby {
+                lobby_id: r.lobby_id,
+                diff: r.diff,
+                region: r.region,
+                host_user_id: r.host_user_id,
+                server_name: r.server_name,
+                numplayers: r.numplayers,
+                classes: r.classes,
+                start: r.start,
+                mods: parse_mods(r.mods)?,
+            })
+        })
+        .collect()
+}
+
+/// Returns the most recent snapshot of a single lobby, however long ago it was seen
+pub async fn latest_snapshot(pool: &SqlitePool, lobby_id: &str) -> Result<Option<Lobby>> {
+    let res = sqlx::query!(
+        r#"SELECT time,
+            lobby_id,
+            diff,
+            region,
+            host_user_id,
+            server_name,
+            numplayers,
+            classes,
+            start,
+            (SELECT json_group_array(json_object('id', mod_id, 'category', category, 'name', name, 'url', url)) FROM
+                (SELECT mod_id, server_mod.category, name, url
+                FROM server_mod
+                JOIN mod USING(mod_id)
+                WHERE
+                    server_mod.time = server.time
+                    AND server_mod.lobby_id = server.lobby_id
+                ORDER BY server_mod.category)
+            ) AS "mods?: String"
+            FROM server
+            WHERE lobby_id = ?
+            ORDER BY time DESC
+            LIMIT 1
+        "#,
+        lobby_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    res.map(|r| {
+        Ok(Lobby {
+            lobby_id: r.lobby_id,
+            diff: r.diff,
+            region: r.region,
+            host_user_id: r.host_user_id,
+            server_name: r.server_name,
+            numplayers: r.numplayers,
+            classes: r.classes,
+            start: r.start,
+            mods: parse_mods(r.mods)?,
+        })
+    })
+    .transpose()
+}
+
+fn parse_mods(mods: Option<String>) -> Result<Vec<Mod>> {
+    match mods {
+        Some(mods) => Ok(serde_json::from_str(&mods)?),
+        None => Ok(vec![]),
+    }
+}
@@ -3,18 +3,44 @@ use sqlx::sqlite::SqlitePool;
 
 use clap::Parser;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use tracing::info;
 
 use std::env;
+use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+mod analysis;
 mod discord;
+mod embed;
+mod event;
+mod filter;
+mod interactions;
+mod lobby;
+mod matrix;
+mod moderation;
+mod optout;
 mod poll;
+mod ratelimit;
+mod report;
+mod settings;
+mod sink;
+mod slack;
+mod steam;
+#[cfg(test)]
+mod testutil;
+mod watch;
+mod webhook;
 mod www;
 
+use settings::Settings;
+
 #[derive(Parser, Clone)]
 struct Config {
+    /// Path to the TOML config file
+    #[arg(long, default_value = "config.toml")]
+    config: PathBuf,
+
     /// Poll current server information
     #[arg(long)]
     poll_servers: bool,
@@ -23,10 +49,62 @@ struct Config {
     #[arg(long)]
     poll_mods: bool,
 
+    /// Analyse mod co-occurrence and detect modpacks
+    #[arg(long)]
+    analyze_mods: bool,
+
     /// Update Discord integration
     #[arg(long)]
     update_discord: bool,
 
+    /// With --update-discord, print the webhook payloads instead of sending them
+    #[arg(long, requires = "update_discord")]
+    dry_run: bool,
+
+    /// Print which recent lobbies match the Discord filter and why
+    #[arg(long)]
+    explain_filter: bool,
+
+    /// Post Discord statistics reports whose period has ended
+    #[arg(long)]
+    post_reports: bool,
+
+    /// Deliver lobby events to the webhooks in the config file, retrying failed deliveries
+    #[arg(long)]
+    deliver_webhooks: bool,
+
+    /// DM users whose watched lobby has a free slot again, run after --poll-servers
+    #[arg(long)]
+    notify_watches: bool,
+
+    /// Hide lobbies matching a moderation rule: `host:<steamid>`, `lobby:<id>` or `name:<pattern>`
+    #[arg(long, value_name = "RULE")]
+    block: Option<String>,
+
+    /// Show lobbies matching a moderation rule even if a block rule matches them too
+    #[arg(long, value_name = "RULE")]
+    allow: Option<String>,
+
+    /// Reason recorded with --block or --allow
+    #[arg(long, default_value = "")]
+    reason: String,
+
+    /// Lift the rule added with --block or --allow after this many hours
+    #[arg(long, value_name = "HOURS", value_parser = clap::value_parser!(i64).range(1..))]
+    expire_hours: Option<i64>,
+
+    /// Remove the moderation rule with this ID
+    #[arg(long, value_name = "ID")]
+    remove_rule: Option<i64>,
+
+    /// List moderation rules
+    #[arg(long)]
+    list_rules: bool,
+
+    /// Register the Discord slash commands answered by the web server
+    #[arg(long)]
+    register_commands: bool,
+
     /// Run web server
     #[arg(long)]
     www: bool,
@@ -42,6 +120,7 @@ async fn main() -> Result<()> {
         .init();
 
     let config = Config::parse();
+    let settings = Settings::load(&config.config)?;
 
     let pool = SqlitePool::connect(&env::var("DATABASE_URL")?).await?;
 
@@ -62,12 +141,72 @@ async fn main() -> Result<()> {
     if config.poll_mods {
         self::poll::update_mods(&pool).await?;
     }
+    if config.analyze_mods {
+        self::analysis::update_mod_analysis(&pool, time).await?;
+    }
+    for (action, spec) in [
+        (self::moderation::RuleAction::Block, &config.block),
+        (self::moderation::RuleAction::Allow, &config.allow),
+    ] {
+        if let Some(spec) = spec {
+            let (kind, value) = self::moderation::parse_rule(spec)?;
+            let rule = self::moderation::NewRule {
+                kind,
+                value: &value,
+                action,
+                reason: &config.reason,
+                expires_at: config.expire_hours.map(|hours| time + hours * 60 * 60),
+            };
+            let rule_id = self::moderation::add_rule(&pool, &rule, time).await?;
+            info!("added moderation rule {}", rule_id);
+        }
+    }
+    if let Some(rule_id) = config.remove_rule {
+        if !self::moderation::remove_rule(&pool, rule_id).await? {
+            bail!("no moderation rule {}", rule_id);
+        }
+    }
+    if config.list_rules {
+        self::moderation::print_rules(&pool, time).await?;
+    }
+    if config.explain_filter {
+        self::discord::explain_filter(&pool, settings.discord()?, time).await?;
+    }
     if config.update_discord {
-        self::discord::update_discord(&pool).await?;
+        let limiter = self::ratelimit::RateLimiter::default();
+        let discord = settings.discord()?;
+        self::discord::update_discord(&pool, discord, &limiter, time, config.dry_run).await?;
+        if !config.dry_run {
+            self::discord::post_category_events(&pool, &limiter).await?;
+            self::discord::announce_new_mods(&pool, &limiter).await?;
+        }
+    }
+
+    if config.post_reports {
+        let limiter = self::ratelimit::RateLimiter::default();
+        self::report::post_reports(&pool, settings.discord()?, &limiter, time).await?;
+    }
+    if config.deliver_webhooks {
+        let client = reqwest::Client::new();
+        self::webhook::deliver_webhooks(&pool, &settings.webhooks, &client, time).await?;
+    }
+    if config.notify_watches {
+        let limiter = self::ratelimit::RateLimiter::default();
+        self::watch::notify_watches(&pool, &limiter, time).await?;
+    }
+    if config.register_commands {
+        self::interactions::register_commands().await?;
     }
 
     if config.www {
-        self::www::run_web_server().await?;
+        // /lobby answers look like the lobbies posted to the first target
+        let embed = settings
+            .discord
+            .as_ref()
+            .and_then(|discord| discord.targets.first())
+            .map(|target| target.embed.clone())
+            .unwrap_or_default();
+        self::www::run_web_server(settings.web.clone(), embed).await?;
     }
 
     Ok(())
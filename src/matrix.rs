@@ -0,0 +1,302 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use crate::discord::{WebhookBody, WebhookEmbed};
+use crate::ratelimit::RateLimiter;
+use crate::sink::{replace_links, unique_id, Delivery, Sink};
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MatrixResponse {
+    Success { event_id: String },
+    Error { errcode: String, error: String },
+}
+
+/// Posts to a Matrix room through the client-server API. Edits are sent as replacement events
+/// and deletions as redactions, the original event ID keeps identifying the message.
+pub struct MatrixSink<'a> {
+    limiter: &'a RateLimiter,
+    homeserver: &'a str,
+    room_id: &'a str,
+    token: String,
+}
+
+impl<'a> MatrixSink<'a> {
+    pub fn new(
+        limiter: &'a RateLimiter,
+        homeserver: &'a str,
+        room_id: &'a str,
+        token: String,
+    ) -> Self {
+        Self {
+            limiter,
+            homeserver,
+            room_id,
+            token,
+        }
+    }
+
+    /// PUTs `data` to `path` below the room, followed by a fresh transaction ID
+    async fn put(&self, path: &str, data: &Value) -> Result<MatrixResponse> {
+        let room = format!(
+            "{}/_matrix/client/v3/rooms/{}",
+            self.homeserver.trim_end_matches('/'),
+            encode_segment(self.room_id)
+        );
+        let request = self
+            .limiter
+            .client()
+            .put(format!("{}/{}/{}", room, path, unique_id()))
+            .bearer_auth(&self.token)
+            .json(data);
+        Ok(self
+            .limiter
+            .send(&format!("PUT {}/{}", room, path), request)
+            .await?
+            .json()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl Sink for MatrixSink<'_> {
+    async fn send(
+        &self,
+        message_id: Option<&str>,
+        thread_id: Option<&str>,
+        body: &WebhookBody,
+    ) -> Result<Delivery> {
+        let (plain, html) = render(body);
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": plain,
+            "format": "org.matrix.custom.html",
+            "formatted_body": html,
+        });
+        let event = match (message_id, thread_id) {
+            (Some(event_id), _) => json!({
+                "msgtype": "m.notice",
+                "body": format!("* {}", plain),
+                "m.new_content": content,
+                "m.relates_to": { "rel_type": "m.replace", "event_id": event_id },
+            }),
+            (None, Some(thread_id)) => {
+                let mut event = content;
+                event["m.relates_to"] = json!({
+                    "rel_type": "m.thread",
+                    "event_id": thread_id,
+                    "is_falling_back": true,
+                    "m.in_reply_to": { "event_id": thread_id },
+                });
+                event
+            }
+            (None, None) => content,
+        };
+
+        match self.put("send/m.room.message", &event).await? {
+            MatrixResponse::Success { event_id } => {
+                // edits are events of their own, the message is still the original event
+                let id = message_id.map(str::to_string).unwrap_or(event_id);
+                let thread_id = match thread_id {
+                    Some(thread_id) => Some(thread_id.to_string()),
+                    None => body.thread_name.as_ref().map(|_| id.clone()),
+                };
+                Ok(Delivery::Sent { id, thread_id })
+            }
+            MatrixResponse::Error { errcode, error } => {
+                if message_id.is_some() && errcode == "M_NOT_FOUND" {
+                    return Ok(Delivery::Gone);
+                }
+                warn!("Received error from Matrix: {} {}", errcode, error);
+                Ok(Delivery::Failed)
+            }
+        }
+    }
+
+    async fn delete(&self, message_id: &str, _thread_id: Option<&str>) -> Result<()> {
+        let path = format!("redact/{}", encode_segment(message_id));
+        match self.put(&path, &json!({})).await? {
+            MatrixResponse::Success { .. } => Ok(()),
+            MatrixResponse::Error { errcode, .. } if errcode == "M_NOT_FOUND" => Ok(()),
+            MatrixResponse::Error { errcode, error } => {
+                bail!("failed to redact {}: {} {}", message_id, errcode, error)
+            }
+        }
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, as room and event IDs contain `!`, `$`
+/// and `:`
+fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Converts Discord markdown to HTML
+fn html(text: &str) -> String {
+    let linked = replace_links(&escape_html(text), |text, url| {
+        format!("<a href=\"{}\">{}</a>", url, text)
+    });
+    let mut out = String::new();
+    for (i, part) in linked.split("**").enumerate() {
+        if i % 2 == 1 {
+            out.push_str(&format!("<strong>{}</strong>", part));
+        } else {
+            out.push_str(part);
+        }
+    }
+    out.replace('\n', "<br>")
+}
+
+fn render_embed(embed: &WebhookEmbed, plain: &mut Vec<String>, html_parts: &mut Vec<String>) {
+    if !embed.title.is_empty() {
+        plain.push(embed.title.clone());
+        html_parts.push(format!("<h4>{}</h4>", escape_html(&embed.title)));
+    }
+    if let Some(author) = &embed.author {
+        plain.push(author.name.clone());
+        let name = match &author.url {
+            Some(url) => format!("[{}]({})", author.name, url),
+            None => author.name.clone(),
+        };
+        html_parts.push(format!("<p><em>{}</em></p>", html(&name)));
+    }
+    if !embed.description.is_empty() {
+        plain.push(embed.description.clone());
+        html_parts.push(format!("<p>{}</p>", html(&embed.description)));
+    }
+    for field in &embed.fields {
+        plain.push(format!("{}: {}", field.name, field.value));
+        html_parts.push(format!(
+            "<p><strong>{}</strong><br>{}</p>",
+            html(&field.name),
+            html(&field.value)
+        ));
+    }
+}
+
+/// Plain text and HTML versions of a message
+fn render(body: &WebhookBody) -> (String, String) {
+    let mut plain = vec![];
+    let mut html_parts = vec![];
+    if let Some(content) = body.content.as_ref().filter(|c| !c.is_empty()) {
+        plain.push(content.clone());
+        html_parts.push(format!("<p>{}</p>", html(content)));
+    }
+    for embed in &body.embeds {
+        render_embed(embed, &mut plain, &mut html_parts);
+    }
+    (plain.join("\n"), html_parts.join(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::WebhookField;
+    use crate::testutil::{fake_service, FakeResponse};
+
+    const SENT: FakeResponse = FakeResponse::new(200, r#"{"event_id":"$event1"}"#);
+    const EDITED: FakeResponse = FakeResponse::new(200, r#"{"event_id":"$event2"}"#);
+    const FORBIDDEN: FakeResponse =
+        FakeResponse::new(403, r#"{"errcode":"M_FORBIDDEN","error":"not in room"}"#);
+
+    fn lobby_body() -> WebhookBody {
+        WebhookBody {
+            embeds: vec![WebhookEmbed {
+                title: "Rock & Stone".to_string(),
+                description: "**Hazard 5** with [Mod](https://mod.io/m/1)".to_string(),
+                fields: vec![WebhookField {
+                    name: "Region".to_string(),
+                    value: "Europe".to_string(),
+                    inline: true,
+                }],
+                ..Default::default()
+            }],
+            thread_name: Some("Rock & Stone".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_html() {
+        let (plain, html) = render(&lobby_body());
+        assert_eq!(
+            plain,
+            "Rock & Stone\n**Hazard 5** with [Mod](https://mod.io/m/1)\nRegion: Europe"
+        );
+        assert_eq!(
+            html,
+            "<h4>Rock &amp; Stone</h4>\
+            <p><strong>Hazard 5</strong> with <a href=\"https://mod.io/m/1\">Mod</a></p>\
+            <p><strong>Region</strong><br>Europe</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_edits_and_thread_replies() {
+        let (server, limiter, url) = fake_service(vec![SENT, EDITED]).await;
+        let sink = MatrixSink::new(&limiter, &url, "!room:example.org", "token".to_string());
+
+        let sent = sink.send(None, None, &lobby_body()).await.unwrap();
+        let thread = Delivery::Sent {
+            id: "$event1".to_string(),
+            thread_id: Some("$event1".to_string()),
+        };
+        assert_eq!(sent, thread);
+        let edited = sink
+            .send(Some("$event1"), Some("$event1"), &lobby_body())
+            .await
+            .unwrap();
+        assert_eq!(edited, thread);
+        let note = WebhookBody {
+            content: Some("Mission started".to_string()),
+            ..Default::default()
+        };
+        sink.send(None, Some("$event1"), &note).await.unwrap();
+
+        let received = server.received().await;
+        for request in &received {
+            assert_eq!(request.method, "PUT");
+            assert!(request.path.starts_with(
+                "/_matrix/client/v3/rooms/%21room%3Aexample.org/send/m.room.message/"
+            ));
+        }
+        let edit: Value = serde_json::from_str(&received[1].body).unwrap();
+        assert_eq!(edit["m.relates_to"]["rel_type"], "m.replace");
+        assert_eq!(edit["m.relates_to"]["event_id"], "$event1");
+        assert_eq!(edit["m.new_content"]["msgtype"], "m.notice");
+        let reply: Value = serde_json::from_str(&received[2].body).unwrap();
+        assert_eq!(reply["m.relates_to"]["rel_type"], "m.thread");
+        assert_eq!(reply["body"], "Mission started");
+    }
+
+    #[tokio::test]
+    async fn refused_messages_fail() {
+        let (server, limiter, url) = fake_service(vec![FORBIDDEN]).await;
+        let sink = MatrixSink::new(&limiter, &url, "!room:example.org", "token".to_string());
+
+        let res = sink.send(None, None, &lobby_body()).await.unwrap();
+        assert_eq!(res, Delivery::Failed);
+        assert!(sink.delete("$event1", None).await.is_err());
+        let received = server.received().await;
+        assert!(received[1].path.contains("/redact/%24event1/"));
+    }
+}
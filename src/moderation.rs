@@ -0,0 +1,377 @@
+//! Runtime block and allow rules for lobbies, applied wherever lobbies are shown
+
+use regex::{Regex, RegexBuilder};
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::warn;
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::optout::opted_out_hosts;
+
+/// What a rule is matched against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    /// Steam ID of the host
+    Host,
+    Lobby,
+    /// Case insensitive pattern matched against the lobby name
+    Name,
+}
+
+impl RuleKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Host => "host",
+            Self::Lobby => "lobby",
+            Self::Name => "name",
+        }
+    }
+}
+
+impl FromStr for RuleKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "host" => Ok(Self::Host),
+            "lobby" => Ok(Self::Lobby),
+            "name" => Ok(Self::Name),
+            other => bail!(
+                "unknown rule kind {:?}, expected host, lobby or name",
+                other
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Hide matching lobbies
+    Block,
+    /// Show matching lobbies even if a block rule matches them too
+    Allow,
+}
+
+impl RuleAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::Allow => "allow",
+        }
+    }
+}
+
+impl FromStr for RuleAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(Self::Block),
+            "allow" => Ok(Self::Allow),
+            other => bail!("unknown rule action {:?}, expected block or allow", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub rule_id: i64,
+    pub kind: RuleKind,
+    pub value: String,
+    pub action: RuleAction,
+    pub reason: String,
+    pub expires_at: Option<i64>,
+}
+
+/// A rule to be added with [`add_rule`]
+#[derive(Debug)]
+pub struct NewRule<'a> {
+    pub kind: RuleKind,
+    pub value: &'a str,
+    pub action: RuleAction,
+    pub reason: &'a str,
+    pub expires_at: Option<i64>,
+}
+
+fn name_pattern(value: &str) -> Result<Regex> {
+    RegexBuilder::new(value)
+        .case_insensitive(true)
+        .build()
+        .with_context(|| format!("invalid name pattern {:?}", value))
+}
+
+/// Checks that `value` can be matched as `kind`
+pub fn validate(kind: RuleKind, value: &str) -> Result<()> {
+    match kind {
+        RuleKind::Host | RuleKind::Lobby => {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                bail!("{} rules need a numeric ID, got {:?}", kind.as_str(), value);
+            }
+        }
+        RuleKind::Name => {
+            name_pattern(value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses rules given on the command line as `host:<steamid>`, `lobby:<id>` or `name:<pattern>`
+pub fn parse_rule(spec: &str) -> Result<(RuleKind, String)> {
+    let (kind, value) = spec
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected <kind>:<value>, got {:?}", spec))?;
+    let kind: RuleKind = kind.parse()?;
+    validate(kind, value)?;
+    Ok((kind, value.to_string()))
+}
+
+/// Adds a rule, returning its ID
+pub async fn add_rule(pool: &SqlitePool, rule: &NewRule<'_>, now: i64) -> Result<i64> {
+    validate(rule.kind, rule.value)?;
+    let kind = rule.kind.as_str();
+    let action = rule.action.as_str();
+    let rule_id = sqlx::query_scalar!(
+        r#"INSERT INTO moderation_rule (kind, value, action, reason, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            RETURNING rule_id AS "rule_id!: i64"
+        "#,
+        kind,
+        rule.value,
+        action,
+        rule.reason,
+        now,
+        rule.expires_at,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(rule_id)
+}
+
+/// Removes a rule, returning whether it existed
+pub async fn remove_rule(pool: &SqlitePool, rule_id: i64) -> Result<bool> {
+    let removed = sqlx::query!("DELETE FROM moderation_rule WHERE rule_id = ?", rule_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(removed > 0)
+}
+
+/// Rules newest first, leaving out those expired at `active_at` if set
+async fn query_rules(pool: &SqlitePool, active_at: Option<i64>) -> Result<Vec<Rule>> {
+    let res = sqlx::query!(
+        r#"SELECT rule_id, kind, value, action, reason, expires_at
+            FROM moderation_rule
+            WHERE ? IS NULL OR expires_at IS NULL OR expires_at > ?
+            ORDER BY rule_id DESC
+        "#,
+        active_at,
+        active_at,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    res.into_iter()
+        .map(|r| {
+            Ok(Rule {
+                rule_id: r.rule_id,
+                kind: r.kind.parse()?,
+                value: r.value,
+                action: r.action.parse()?,
+                reason: r.reason,
+                expires_at: r.expires_at,
+            })
+        })
+        .collect()
+}
+
+/// Every rule including expired ones, newest first
+pub async fn list_rules(pool: &SqlitePool) -> Result<Vec<Rule>> {
+    query_rules(pool, None).await
+}
+
+/// Prints every rule for `--list-rules`
+pub async fn print_rules(pool: &SqlitePool, now: i64) -> Result<()> {
+    for rule in list_rules(pool).await? {
+        let expiry = match rule.expires_at {
+            Some(expires_at) if expires_at <= now => " (expired)".to_string(),
+            Some(expires_at) => format!(" (expires in {}m)", (expires_at - now) / 60),
+            None => String::new(),
+        };
+        println!(
+            "{:>5} {} {}:{}{} {}",
+            rule.rule_id,
+            rule.action.as_str(),
+            rule.kind.as_str(),
+            rule.value,
+            expiry,
+            rule.reason
+        );
+    }
+    Ok(())
+}
+
+/// The rules in effect, loaded once per request or update
+#[derive(Debug, Default)]
+pub struct Moderation {
+    rules: Vec<(Rule, Option<Regex>)>,
+    /// Hosts that opted out, hidden regardless of any rule
+    opted_out: HashSet<String>,
+}
+
+impl Moderation {
+    /// Loads the rules that have not expired at `now`
+    pub async fn load(pool: &SqlitePool, now: i64) -> Result<Self> {
+        let mut rules = vec![];
+        for rule in query_rules(pool, Some(now)).await? {
+            let pattern = match rule.kind {
+                RuleKind::Name => match name_pattern(&rule.value) {
+                    Ok(pattern) => Some(pattern),
+                    Err(e) => {
+                        warn!("skipping moderation rule {}: {:#}", rule.rule_id, e);
+                        continue;
+                    }
+                },
+                RuleKind::Host | RuleKind::Lobby => None,
+            };
+            rules.push((rule, pattern));
+        }
+        Ok(Self {
+            rules,
+            opted_out: opted_out_hosts(pool).await?,
+        })
+    }
+
+    pub fn is_opted_out(&self, host_user_id: &str) -> bool {
+        self.opted_out.contains(host_user_id)
+    }
+
+    fn matching(
+        &self,
+        action: RuleAction,
+        host_user_id: &str,
+        lobby_id: &str,
+        server_name: &str,
+    ) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|(rule, _)| rule.action == action)
+            .find(|(rule, pattern)| match rule.kind {
+                RuleKind::Host => rule.value == host_user_id,
+                RuleKind::Lobby => rule.value == lobby_id,
+                RuleKind::Name => pattern.as_ref().is_some_and(|p| p.is_match(server_name)),
+            })
+            .map(|(rule, _)| rule)
+    }
+
+    /// The block rule hiding a lobby, unless an allow rule matches the lobby as well
+    pub fn blocking_rule(
+        &self,
+        host_user_id: &str,
+        lobby_id: &str,
+        server_name: &str,
+    ) -> Option<&Rule> {
+        if self
+            .matching(RuleAction::Allow, host_user_id, lobby_id, server_name)
+            .is_some()
+        {
+            return None;
+        }
+        self.matching(RuleAction::Block, host_user_id, lobby_id, server_name)
+    }
+
+    pub fn hides(&self, host_user_id: &str, lobby_id: &str, server_name: &str) -> bool {
+        self.is_opted_out(host_user_id)
+            || self
+                .blocking_rule(host_user_id, lobby_id, server_name)
+                .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        rule_id: i64,
+        kind: RuleKind,
+        value: &str,
+        action: RuleAction,
+    ) -> (Rule, Option<Regex>) {
+        let pattern = (kind == RuleKind::Name).then(|| name_pattern(value).unwrap());
+        (
+            Rule {
+                rule_id,
+                kind,
+                value: value.to_string(),
+                action,
+                reason: String::new(),
+                expires_at: None,
+            },
+            pattern,
+        )
+    }
+
+    #[test]
+    fn blocks_hosts_lobbies_and_names() {
+        let moderation = Moderation {
+            rules: vec![
+                rule(1, RuleKind::Host, "76561198000000001", RuleAction::Block),
+                rule(2, RuleKind::Lobby, "1234", RuleAction::Block),
+                rule(3, RuleKind::Name, r"discord\.gg", RuleAction::Block),
+            ],
+            ..Default::default()
+        };
+        assert!(moderation.hides("76561198000000001", "1", "Rock and Stone"));
+        assert!(moderation.hides("76561198000000002", "1234", "Rock and Stone"));
+        let rule = moderation.blocking_rule("76561198000000002", "1", "join DISCORD.GG/spam");
+        assert_eq!(rule.map(|r| r.rule_id), Some(3));
+        assert!(!moderation.hides("76561198000000002", "1", "Rock and Stone"));
+    }
+
+    #[test]
+    fn allow_rules_override_blocks() {
+        let moderation = Moderation {
+            rules: vec![
+                rule(1, RuleKind::Name, "discord", RuleAction::Block),
+                rule(2, RuleKind::Host, "76561198000000001", RuleAction::Allow),
+            ],
+            ..Default::default()
+        };
+        assert!(!moderation.hides("76561198000000001", "1", "our discord server"));
+        assert!(moderation.hides("76561198000000002", "1", "our discord server"));
+    }
+
+    #[test]
+    fn opted_out_hosts_cannot_be_allowed() {
+        let moderation = Moderation {
+            rules: vec![rule(
+                1,
+                RuleKind::Host,
+                "76561198000000001",
+                RuleAction::Allow,
+            )],
+            opted_out: HashSet::from(["76561198000000001".to_string()]),
+        };
+        assert!(moderation.hides("76561198000000001", "1", "Rock and Stone"));
+        assert!(!moderation.hides("76561198000000002", "1", "Rock and Stone"));
+    }
+
+    #[test]
+    fn parses_command_line_rules() {
+        assert_eq!(
+            parse_rule("host:76561198000000001").unwrap(),
+            (RuleKind::Host, "76561198000000001".to_string())
+        );
+        assert_eq!(
+            parse_rule("name:a:b").unwrap(),
+            (RuleKind::Name, "a:b".to_string())
+        );
+        assert!(parse_rule("host:someone").is_err());
+        assert!(parse_rule("name:(").is_err());
+        assert!(parse_rule("player:1").is_err());
+        assert!(parse_rule("76561198000000001").is_err());
+    }
+}
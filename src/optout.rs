@@ -0,0 +1,297 @@
+//! Self-service opt-out of hosts, who prove owning their Steam account by signing in through
+//! Steam's OpenID 2.0 provider
+
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+const OPENID_NS: &str = "http://specs.openid.net/auth/2.0";
+/// Lets the provider choose the identity, i.e. the account the user signs in with
+const IDENTIFIER_SELECT: &str = "http://specs.openid.net/auth/2.0/identifier_select";
+const STEAM_ID_PREFIX: &str = "https://steamcommunity.com/openid/id/";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a signed in host asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    /// Stop listing their lobbies
+    OptOut,
+    /// List their lobbies again
+    OptIn,
+}
+
+impl Choice {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::OptOut => "out",
+            Self::OptIn => "in",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [Self::OptOut, Self::OptIn]
+            .into_iter()
+            .find(|c| c.as_str() == s)
+    }
+}
+
+/// Where the provider sends the user back to. The choice is part of it, so it is covered by the
+/// provider's signature.
+fn return_url(public_url: &str, choice: Choice) -> String {
+    format!(
+        "{}/optout/verify?choice={}",
+        public_url.trim_end_matches('/'),
+        choice.as_str()
+    )
+}
+
+/// Sign in page of the provider, which sends the user back to `/optout/verify` with a signed
+/// assertion of their Steam account
+pub fn login_url(endpoint: &str, public_url: &str, choice: Choice) -> Result<String> {
+    let return_to = return_url(public_url, choice);
+    let query = serde_urlencoded::to_string([
+        ("openid.ns", OPENID_NS),
+        ("openid.mode", "checkid_setup"),
+        ("openid.return_to", &return_to),
+        ("openid.realm", public_url.trim_end_matches('/')),
+        ("openid.identity", IDENTIFIER_SELECT),
+        ("openid.claimed_id", IDENTIFIER_SELECT),
+    ])?;
+    Ok(format!("{}?{}", endpoint, query))
+}
+
+/// Checks the assertion the user was sent back with, given as the query parameters of
+/// `/optout/verify`, by asking the provider to confirm its signature. Returns the Steam ID of the
+/// signed in account and what the user chose.
+pub async fn verify(
+    client: &reqwest::Client,
+    endpoint: &str,
+    public_url: &str,
+    params: &[(String, String)],
+) -> Result<(String, Choice)> {
+    let get = |name: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+
+    ensure!(
+        get("openid.mode") == Some("id_res"),
+        "signing in was cancelled or failed"
+    );
+    ensure!(
+        get("openid.op_endpoint") == Some(endpoint),
+        "the assertion was made by another provider"
+    );
+    let return_to = get("openid.return_to").context("the assertion has no return URL")?;
+    let choice = [Choice::OptOut, Choice::OptIn]
+        .into_iter()
+        .find(|c| return_url(public_url, *c) == return_to)
+        .context("the assertion was made for another site")?;
+    let signed: Vec<&str> = get("openid.signed")
+        .unwrap_or_default()
+        .split(',')
+        .collect();
+    for field in [
+        "op_endpoint",
+        "claimed_id",
+        "identity",
+        "return_to",
+        "response_nonce",
+    ] {
+        ensure!(signed.contains(&field), "openid.{} is not signed", field);
+    }
+    let steam_id = get("openid.claimed_id")
+        .and_then(|id| id.strip_prefix(STEAM_ID_PREFIX))
+        .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+        .context("the assertion is not for a Steam account")?;
+
+    // the provider checks the signature and that the nonce was not used before
+    let mut check: Vec<(&str, &str)> = params
+        .iter()
+        .filter(|(k, _)| k.starts_with("openid.") && k != "openid.mode")
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    check.push(("openid.mode", "check_authentication"));
+    let res = client
+        .post(endpoint)
+        .timeout(TIMEOUT)
+        .form(&check)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    if !res.lines().any(|l| l.trim() == "is_valid:true") {
+        bail!("the provider did not confirm the assertion");
+    }
+
+    Ok((steam_id.to_string(), choice))
+}
+
+/// Records the choice of a signed in host
+pub async fn set_choice(
+    pool: &SqlitePool,
+    host_user_id: &str,
+    choice: Choice,
+    now: i64,
+) -> Result<()> {
+    match choice {
+        Choice::OptOut => {
+            sqlx::query!(
+                "INSERT INTO host_optout (host_user_id, created_at) VALUES (?, ?) ON CONFLICT(host_user_id) DO NOTHING",
+                host_user_id,
+                now,
+            )
+            .execute(pool)
+            .await?;
+        }
+        Choice::OptIn => {
+            sqlx::query!(
+                "DELETE FROM host_optout WHERE host_user_id = ?",
+                host_user_id
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Steam IDs of the hosts that opted out
+pub async fn opted_out_hosts(pool: &SqlitePool) -> Result<HashSet<String>> {
+    Ok(sqlx::query_scalar!("SELECT host_user_id FROM host_optout")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{FakeResponse, FakeServer};
+
+    const PUBLIC_URL: &str = "https://drg.example.com";
+
+    fn assertion(endpoint: &str, return_to: &str) -> Vec<(String, String)> {
+        [
+            ("choice", "out"),
+            ("openid.ns", OPENID_NS),
+            ("openid.mode", "id_res"),
+            ("openid.op_endpoint", endpoint),
+            (
+                "openid.claimed_id",
+                "https://steamcommunity.com/openid/id/76561198000000001",
+            ),
+            (
+                "openid.identity",
+                "https://steamcommunity.com/openid/id/76561198000000001",
+            ),
+            ("openid.return_to", return_to),
+            ("openid.response_nonce", "2026-10-19T00:00:00Zabc"),
+            ("openid.assoc_handle", "1234567890"),
+            (
+                "openid.signed",
+                "signed,op_endpoint,claimed_id,identity,return_to,response_nonce,assoc_handle",
+            ),
+            ("openid.sig", "c2lnbmF0dXJl"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn builds_login_url() {
+        let url = login_url(
+            "https://steamcommunity.com/openid/login",
+            "https://drg.example.com/",
+            Choice::OptOut,
+        )
+        .unwrap();
+        let (endpoint, query) = url.split_once('?').unwrap();
+        assert_eq!(endpoint, "https://steamcommunity.com/openid/login");
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap();
+        assert!(params.contains(&(
+            "openid.return_to".to_string(),
+            "https://drg.example.com/optout/verify?choice=out".to_string()
+        )));
+        assert!(params.contains(&(
+            "openid.realm".to_string(),
+            "https://drg.example.com".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn verifies_with_provider() {
+        let server = FakeServer::start(vec![FakeResponse::new(
+            200,
+            "ns:http://specs.openid.net/auth/2.0\nis_valid:true\n",
+        )])
+        .await;
+        let endpoint = server.url();
+        let client = reqwest::Client::new();
+
+        let params = assertion(&endpoint, &return_url(PUBLIC_URL, Choice::OptOut));
+        let (steam_id, choice) = verify(&client, &endpoint, PUBLIC_URL, &params)
+            .await
+            .unwrap();
+        assert_eq!(steam_id, "76561198000000001");
+        assert_eq!(choice, Choice::OptOut);
+
+        let request = &server.received().await[0];
+        assert_eq!(request.method, "POST");
+        let sent: Vec<(String, String)> = serde_urlencoded::from_str(&request.body).unwrap();
+        assert!(sent.contains(&(
+            "openid.mode".to_string(),
+            "check_authentication".to_string()
+        )));
+        assert!(sent.contains(&("openid.sig".to_string(), "c2lnbmF0dXJl".to_string())));
+        assert!(!sent.iter().any(|(k, _)| k == "choice"));
+    }
+
+    #[tokio::test]
+    async fn rejects_unconfirmed_assertions() {
+        let server = FakeServer::start(vec![FakeResponse::new(
+            200,
+            "ns:http://specs.openid.net/auth/2.0\nis_valid:false\n",
+        )])
+        .await;
+        let endpoint = server.url();
+        let client = reqwest::Client::new();
+
+        let params = assertion(&endpoint, &return_url(PUBLIC_URL, Choice::OptOut));
+        assert!(verify(&client, &endpoint, PUBLIC_URL, &params)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_assertions_for_other_sites() {
+        let client = reqwest::Client::new();
+        let endpoint = "http://127.0.0.1:9/openid/login";
+
+        let params = assertion(
+            endpoint,
+            "https://evil.example.com/optout/verify?choice=out",
+        );
+        let err = verify(&client, endpoint, PUBLIC_URL, &params)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the assertion was made for another site");
+
+        let params = assertion(endpoint, &return_url(PUBLIC_URL, Choice::OptOut));
+        let err = verify(&client, "http://127.0.0.1:9/other", PUBLIC_URL, &params)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the assertion was made by another provider"
+        );
+    }
+}
@@ -137,6 +137,110 @@ pub async fn update_server_list(pool: &SqlitePool, time: i64) -> Result<()> {
     for server in servers.values() {
         insert_server(pool, time, server).await?;
     }
+    crate::event::record_events(pool, time).await?;
+
+    let hosts: Vec<&str> = servers.values().map(|s| s.host_user_id.as_str()).collect();
+    crate::steam::get_players(pool, &hosts).await?;
+
+    update_mod_categories(pool, time).await?;
+
+    Ok(())
+}
+
+/// Number of consecutive snapshots of a mod a new category has to win before it is recorded, so
+/// lobbies briefly disagreeing about a mod do not flip its category back and forth
+const CATEGORY_SNAPSHOTS: usize = 3;
+
+/// Category that won every one of the snapshots in `rows`, given as `(time, category, lobbies)`
+/// ordered by time and then by lobbies descending, if there are [`CATEGORY_SNAPSHOTS`] of them
+fn settled_category(rows: impl IntoIterator<Item = (i64, i64, i64)>) -> Option<i64> {
+    let winners: Vec<i64> = rows
+        .into_iter()
+        .unique_by(|(time, _, _)| *time)
+        .map(|(_, category, _)| category)
+        .collect();
+    let first = *winners.first()?;
+    (winners.len() == CATEGORY_SNAPSHOTS && winners.iter().all(|&c| c == first)).then_some(first)
+}
+
+/// Compares the category each mod was reported with in the snapshot at `time` against the last
+/// known category and records an event for every mod that changed. When lobbies disagree, the
+/// category reported by the most lobbies wins, and a change only counts once the new category
+/// won the last [`CATEGORY_SNAPSHOTS`] snapshots the mod was seen in.
+#[tracing::instrument(skip(pool))]
+async fn update_mod_categories(pool: &SqlitePool, time: i64) -> Result<()> {
+    let observed = sqlx::query!(
+        r#"SELECT mod_id, category, COUNT(*) AS "lobbies!: i64"
+            FROM server_mod
+            WHERE time = ?
+            GROUP BY mod_id, category
+            ORDER BY mod_id, 3 DESC, category
+        "#,
+        time,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let known: std::collections::HashMap<i64, Option<i64>> =
+        sqlx::query!("SELECT mod_id, current_category FROM mod")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|r| (r.mod_id, r.current_category))
+            .collect();
+
+    let mut tx = pool.begin().await?;
+    // rows are ordered by lobby count so the first row of each mod is the majority category
+    for r in observed.into_iter().unique_by(|r| r.mod_id) {
+        let (mod_id, category) = (r.mod_id, r.category);
+        let previous = known.get(&mod_id).copied().flatten();
+
+        if previous == Some(category) {
+            continue;
+        }
+        if let Some(previous) = previous {
+            let limit = CATEGORY_SNAPSHOTS as i64;
+            let recent = sqlx::query!(
+                r#"SELECT time, category, COUNT(*) AS "lobbies!: i64"
+                    FROM server_mod
+                    WHERE mod_id = ? AND time IN (
+                        SELECT DISTINCT time FROM server_mod WHERE mod_id = ? ORDER BY time DESC LIMIT ?
+                    )
+                    GROUP BY time, category
+                    ORDER BY time, 3 DESC, category
+                "#,
+                mod_id,
+                mod_id,
+                limit,
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+            let recent = recent.into_iter().map(|r| (r.time, r.category, r.lobbies));
+            if settled_category(recent) != Some(category) {
+                continue;
+            }
+            info!("mod {mod_id} changed category from {previous} to {category}");
+            sqlx::query!(
+                "INSERT INTO mod_category_event (mod_id, time, old_category, new_category) VALUES (?, ?, ?, ?)",
+                mod_id,
+                time,
+                previous,
+                category
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            "UPDATE mod SET current_category = ?, current_category_time = ? WHERE mod_id = ?",
+            category,
+            time,
+            mod_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
     Ok(())
 }
 
@@ -272,9 +376,28 @@ VALUES ( ?, ?, ?, ?, ? )
     )
     .execute(pool)
     .await?;
-    sqlx::query!("INSERT OR IGNORE INTO mod (mod_id) VALUES ( ? )", m.name)
-        .execute(pool)
-        .await?;
+
+    // only public lobbies count towards when a mod was first seen
+    let (first_seen, first_lobby_id) = if server.password_requires == 0 {
+        (Some(time), Some(&server.id))
+    } else {
+        (None, None)
+    };
+    sqlx::query!(
+        r#"
+INSERT INTO mod ( mod_id, first_seen, first_lobby_id )
+VALUES ( ?, ?, ? )
+ON CONFLICT(mod_id) DO UPDATE SET
+    first_seen = excluded.first_seen,
+    first_lobby_id = excluded.first_lobby_id
+WHERE mod.first_seen IS NULL
+        "#,
+        m.name,
+        first_seen,
+        first_lobby_id
+    )
+    .execute(pool)
+    .await?;
     Ok(())
 }
 
@@ -309,3 +432,25 @@ async fn get_server_list(difficulty_bitset: u8) -> Result<ServerList> {
         .await?;
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settles_on_a_category_won_in_every_snapshot() {
+        let rows = [(1, 2, 5), (1, 0, 1), (2, 2, 4), (3, 2, 3)];
+        assert_eq!(settled_category(rows), Some(2));
+    }
+
+    #[test]
+    fn flapping_categories_do_not_settle() {
+        let rows = [(1, 2, 5), (2, 0, 4), (2, 2, 1), (3, 2, 3)];
+        assert_eq!(settled_category(rows), None);
+    }
+
+    #[test]
+    fn too_few_snapshots_do_not_settle() {
+        assert_eq!(settled_category([(1, 2, 5), (2, 2, 4)]), None);
+    }
+}
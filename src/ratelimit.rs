@@ -0,0 +1,334 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{info, warn};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Number of times a ratelimited request is retried before giving up
+const MAX_RETRIES: u32 = 5;
+
+#[derive(Debug, Deserialize)]
+struct RatelimitBody {
+    retry_after: f32,
+    #[serde(default)]
+    global: bool,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    remaining: u32,
+    reset: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Bucket of each route, learned from the `x-ratelimit-bucket` header
+    routes: HashMap<String, String>,
+    buckets: HashMap<String, Bucket>,
+    /// Set while the global ratelimit is exhausted
+    global_reset: Option<Instant>,
+}
+
+/// Shares Discord ratelimit state between requests. Routes are mapped to the buckets Discord
+/// reports for them, so requests in different buckets are sent concurrently while requests
+/// sharing an exhausted bucket wait for it to reset instead of being rejected.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    client: reqwest::Client,
+    state: Arc<Mutex<State>>,
+    max_retries: u32,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(MAX_RETRIES)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            state: Default::default(),
+            max_retries,
+        }
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Sends `request` once its route's bucket has capacity, retrying up to the configured number
+    /// of times if Discord still responds with 429. `route` identifies the endpoint, see
+    /// [`route`].
+    pub async fn send(&self, route: &str, request: RequestBuilder) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            self.wait(route).await;
+
+            let res = request
+                .try_clone()
+                .context("request body cannot be retried")?
+                .send()
+                .await
+                // the URL may contain a webhook token
+                .map_err(|e| e.without_url())?;
+
+            if res.status() != StatusCode::TOO_MANY_REQUESTS {
+                self.update(route, &res).await;
+                return Ok(res);
+            }
+
+            let retry_after_header = header::<f32>(&res, "retry-after");
+            let body = res.json::<RatelimitBody>().await.ok();
+            let retry_after = body
+                .as_ref()
+                .map(|b| b.retry_after)
+                .or(retry_after_header)
+                .unwrap_or(1.0);
+            let global = body.is_some_and(|b| b.global);
+            let reset = Instant::now() + Duration::from_secs_f32(retry_after);
+
+            {
+                let mut state = self.state.lock().await;
+                if global {
+                    state.global_reset = Some(reset);
+                } else if let Some(bucket) = state.routes.get(route).cloned() {
+                    state.buckets.insert(
+                        bucket,
+                        Bucket {
+                            remaining: 0,
+                            reset,
+                        },
+                    );
+                }
+            }
+
+            if retries >= self.max_retries {
+                bail!("{route} still ratelimited after {retries} retries");
+            }
+            retries += 1;
+            warn!("{route} ratelimited, global: {global}, retry_after: {retry_after}");
+            if !global {
+                sleep_until(reset).await;
+            }
+        }
+    }
+
+    /// Waits until neither the global limit nor the route's bucket is exhausted, then reserves a
+    /// request from the bucket
+    async fn wait(&self, route: &str) {
+        loop {
+            let until = {
+                let mut state = self.state.lock().await;
+                let State {
+                    routes,
+                    buckets,
+                    global_reset,
+                } = &mut *state;
+                let now = Instant::now();
+
+                if let Some(reset) = global_reset.filter(|reset| *reset > now) {
+                    Some(reset)
+                } else {
+                    match routes.get(route).and_then(|id| buckets.get_mut(id)) {
+                        Some(bucket) if bucket.reset > now && bucket.remaining == 0 => {
+                            Some(bucket.reset)
+                        }
+                        Some(bucket) => {
+                            bucket.remaining = bucket.remaining.saturating_sub(1);
+                            None
+                        }
+                        None => None,
+                    }
+                }
+            };
+            match until {
+                Some(until) => {
+                    info!("waiting {:?} for {route}", until - Instant::now());
+                    sleep_until(until).await
+                }
+                None => return,
+            }
+        }
+    }
+
+    async fn update(&self, route: &str, res: &Response) {
+        let (Some(bucket), Some(remaining), Some(reset_after)) = (
+            header::<String>(res, "x-ratelimit-bucket"),
+            header::<u32>(res, "x-ratelimit-remaining"),
+            header::<f32>(res, "x-ratelimit-reset-after"),
+        ) else {
+            return;
+        };
+
+        let mut state = self.state.lock().await;
+        state.routes.insert(route.to_string(), bucket.clone());
+        state.buckets.insert(
+            bucket,
+            Bucket {
+                remaining,
+                reset: Instant::now() + Duration::from_secs_f32(reset_after),
+            },
+        );
+    }
+}
+
+/// Route key of `method` requests to `url`. Webhook URLs contain their token, so only the host and
+/// a hash of the URL are kept, as route keys show up in logs and errors.
+pub fn route(method: &str, url: &str) -> String {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let digest = Sha256::digest(url.as_bytes());
+    format!("{} {}#{}", method, host, hex::encode(&digest[..8]))
+}
+
+fn header<T: std::str::FromStr>(res: &Response, name: &str) -> Option<T> {
+    res.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::{FakeResponse, FakeServer};
+
+    const OK: FakeResponse = FakeResponse::new(200, r#"{"id":"1"}"#);
+    const LIMITED: FakeResponse = FakeResponse::new(
+        429,
+        r#"{"message":"You are being rate limited.","retry_after":0.05,"global":false}"#,
+    );
+    const GLOBAL_LIMITED: FakeResponse = FakeResponse::new(
+        429,
+        r#"{"message":"You are being rate limited.","retry_after":0.3,"global":true}"#,
+    );
+    const EXHAUSTED: FakeResponse = FakeResponse {
+        status: 200,
+        headers: &[
+            ("x-ratelimit-bucket", "abc"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset-after", "0.3"),
+        ],
+        body: r#"{"id":"1"}"#,
+    };
+
+    #[test]
+    fn routes_hide_webhook_tokens() {
+        let url = "https://discord.com/api/webhooks/1234/secret-token";
+        let route = route("POST", url);
+        assert!(route.starts_with("POST discord.com#"));
+        assert!(!route.contains("secret-token"));
+        assert_eq!(route, super::route("POST", url));
+        assert_ne!(route, super::route("PATCH", url));
+        assert_ne!(
+            route,
+            super::route("POST", "https://discord.com/api/webhooks/1234/other-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_after_429() {
+        let server = FakeServer::start(vec![LIMITED, LIMITED, OK]).await;
+        let limiter = RateLimiter::new(5);
+
+        let res = limiter
+            .send(
+                "POST webhook",
+                limiter.client().post(server.url()).body("{}"),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(server.requests(), 3);
+        // every retry resends the same request
+        for request in server.received().await {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/");
+            assert_eq!(request.body, "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = FakeServer::start(vec![LIMITED]).await;
+        let limiter = RateLimiter::new(2);
+
+        let res = limiter
+            .send("POST webhook", limiter.client().post(server.url()))
+            .await;
+
+        assert!(res.is_err());
+        assert_eq!(server.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn waits_for_exhausted_bucket() {
+        let server = FakeServer::start(vec![EXHAUSTED, OK]).await;
+        let limiter = RateLimiter::new(5);
+
+        limiter
+            .send("POST webhook", limiter.client().post(server.url()))
+            .await
+            .unwrap();
+        let start = Instant::now();
+        limiter
+            .send("POST webhook", limiter.client().post(server.url()))
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(server.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn unrelated_routes_do_not_wait() {
+        let server = FakeServer::start(vec![EXHAUSTED, OK]).await;
+        let limiter = RateLimiter::new(5);
+
+        limiter
+            .send("POST webhook-a", limiter.client().post(server.url()))
+            .await
+            .unwrap();
+        let start = Instant::now();
+        limiter
+            .send("POST webhook-b", limiter.client().post(server.url()))
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() < Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn global_limit_blocks_all_routes() {
+        let server = FakeServer::start(vec![GLOBAL_LIMITED, OK, OK]).await;
+        let limiter = RateLimiter::new(5);
+
+        let start = Instant::now();
+        let (a, b) = tokio::join!(
+            limiter.send("POST webhook-a", limiter.client().post(server.url())),
+            async {
+                // let the first request hit the global limit before sending
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                limiter
+                    .send("POST webhook-b", limiter.client().post(server.url()))
+                    .await
+            }
+        );
+
+        a.unwrap();
+        b.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(server.requests(), 3);
+    }
+}
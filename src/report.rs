@@ -0,0 +1,270 @@
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use crate::discord::{
+    join_lines, post_webhook, WebhookBody, WebhookEmbed, WebhookField, WebhookResponse,
+};
+use crate::ratelimit::RateLimiter;
+use crate::settings::{DiscordSettings, ReportPeriod, ReportSettings};
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// Start and end of the last complete period before `now`
+fn last_period(period: ReportPeriod, now: i64) -> (i64, i64) {
+    let today = now - now.rem_euclid(DAY);
+    match period {
+        ReportPeriod::Daily => (today - DAY, today),
+        ReportPeriod::Weekly => {
+            // 1970-01-01 was a Thursday
+            let monday = today - (today / DAY + 3).rem_euclid(7) * DAY;
+            (monday - 7 * DAY, monday)
+        }
+    }
+}
+
+/// Posts every configured report whose last complete period has not been reported yet. A report
+/// is claimed in the `report` table before it is posted, so concurrent or repeated runs never
+/// post it twice.
+#[tracing::instrument(skip_all)]
+pub async fn post_reports(
+    pool: &SqlitePool,
+    settings: &DiscordSettings,
+    limiter: &RateLimiter,
+    now: i64,
+) -> Result<()> {
+    for report in &settings.reports {
+        let webhook = std::env::var(&report.webhook_env)
+            .with_context(|| format!("report {:?} requires {}", report.name, report.webhook_env))?;
+        let (start, end) = last_period(report.period, now);
+        let claimed = sqlx::query!(
+            "INSERT INTO report(name, period_start, period_end, claimed_at) VALUES (?, ?, ?, ?) ON CONFLICT(name, period_start) DO NOTHING",
+            report.name,
+            start,
+            end,
+            now,
+        )
+        .execute(pool)
+        .await?
+        .rows_affected()
+            == 1;
+        if !claimed {
+            continue;
+        }
+
+        match post_report(pool, limiter, report, &webhook, start, end).await {
+            Ok(Some(message_id)) => {
+                info!("posted report {} for {}", report.name, start);
+                sqlx::query!(
+                    "UPDATE report SET message_id = ? WHERE name = ? AND period_start = ?",
+                    message_id,
+                    report.name,
+                    start,
+                )
+                .execute(pool)
+                .await?;
+            }
+            res => {
+                // release the claim so the next run tries again
+                sqlx::query!(
+                    "DELETE FROM report WHERE name = ? AND period_start = ?",
+                    report.name,
+                    start,
+                )
+                .execute(pool)
+                .await?;
+                res?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn post_report(
+    pool: &SqlitePool,
+    limiter: &RateLimiter,
+    report: &ReportSettings,
+    webhook: &str,
+    start: i64,
+    end: i64,
+) -> Result<Option<String>> {
+    let data = WebhookBody {
+        embeds: vec![report_embed(pool, report.period, start, end).await?],
+        ..Default::default()
+    }
+    .fit_limits();
+
+    match post_webhook(limiter, webhook, &data).await? {
+        WebhookResponse::Success { id, .. } => Ok(Some(id)),
+        WebhookResponse::Error { message, code } => {
+            warn!("Received error from endpoint: {} code: {}", message, code);
+            Ok(None)
+        }
+    }
+}
+
+async fn report_embed(
+    pool: &SqlitePool,
+    period: ReportPeriod,
+    start: i64,
+    end: i64,
+) -> Result<WebhookEmbed> {
+    let lobbies = sqlx::query_scalar!(
+        r#"SELECT COUNT(DISTINCT lobby_id) AS "lobbies!: i64" FROM server WHERE time >= ? AND time < ?"#,
+        start,
+        end,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let peak = sqlx::query!(
+        r#"SELECT time, COUNT(*) AS "lobbies!: i64"
+            FROM server
+            WHERE time >= ? AND time < ?
+            GROUP BY time
+            ORDER BY COUNT(*) DESC, time
+            LIMIT 1
+        "#,
+        start,
+        end,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let hazards: Vec<String> = sqlx::query!(
+        r#"SELECT diff + 1 AS "hazard!: i64", COUNT(DISTINCT lobby_id) AS "lobbies!: i64"
+            FROM server
+            WHERE time >= ? AND time < ?
+            GROUP BY diff
+            ORDER BY diff
+        "#,
+        start,
+        end,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| format!("Hazard {}: {}", r.hazard, r.lobbies))
+    .collect();
+
+    let regions: Vec<String> = sqlx::query!(
+        r#"SELECT region, COUNT(DISTINCT lobby_id) AS "lobbies!: i64"
+            FROM server
+            WHERE time >= ? AND time < ?
+            GROUP BY region
+            ORDER BY COUNT(DISTINCT lobby_id) DESC, region
+        "#,
+        start,
+        end,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| format!("{}: {}", r.region, r.lobbies))
+    .collect();
+
+    let top_mods: Vec<String> = sqlx::query!(
+        r#"SELECT mod_id, name, url, COUNT(DISTINCT lobby_id) AS "lobbies!: i64"
+            FROM server_mod
+            JOIN mod USING(mod_id)
+            WHERE time >= ? AND time < ?
+            GROUP BY mod_id
+            ORDER BY COUNT(DISTINCT lobby_id) DESC, mod_id
+            LIMIT 10
+        "#,
+        start,
+        end,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .enumerate()
+    .map(|(i, m)| {
+        format!(
+            "{}. {} ({})",
+            i + 1,
+            mod_link(m.mod_id, m.name, m.url),
+            m.lobbies
+        )
+    })
+    .collect();
+
+    let new_mods: Vec<String> = sqlx::query!(
+        "SELECT mod_id, name, url FROM mod WHERE first_seen >= ? AND first_seen < ? ORDER BY first_seen",
+        start,
+        end,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|m| mod_link(m.mod_id, m.name, m.url))
+    .collect();
+
+    let hours: Vec<String> = sqlx::query!(
+        r#"SELECT CAST(strftime('%H', time, 'unixepoch') AS INTEGER) AS "hour!: i64",
+            AVG(lobbies) AS "lobbies!: f64"
+            FROM (SELECT time, COUNT(*) AS lobbies FROM server WHERE time >= ? AND time < ? GROUP BY time)
+            GROUP BY strftime('%H', time, 'unixepoch')
+            ORDER BY AVG(lobbies) DESC
+            LIMIT 3
+        "#,
+        start,
+        end,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| format!("{:02}:00 UTC: {:.1}", r.hour, r.lobbies))
+    .collect();
+
+    let mut fields = vec![WebhookField {
+        name: "Lobbies".to_string(),
+        value: lobbies.to_string(),
+        inline: true,
+    }];
+    if let Some(peak) = peak {
+        fields.push(WebhookField {
+            name: "Peak concurrent".to_string(),
+            value: format!("{} at <t:{}:f>", peak.lobbies, peak.time),
+            inline: true,
+        });
+    }
+    for (name, lines, inline) in [
+        ("By hazard", hazards, true),
+        ("By region", regions, true),
+        ("Busiest hours (avg lobbies)", hours, true),
+        ("Top mods", top_mods, false),
+        ("New mods", new_mods, false),
+    ] {
+        if !lines.is_empty() {
+            fields.push(WebhookField {
+                name: name.to_string(),
+                value: join_lines(&lines, 1024),
+                inline,
+            });
+        }
+    }
+
+    let (title, description) = match period {
+        ReportPeriod::Daily => ("Daily report", format!("<t:{}:D>", start)),
+        ReportPeriod::Weekly => (
+            "Weekly report",
+            format!("<t:{}:D> to <t:{}:D>", start, end - 1),
+        ),
+    };
+    Ok(WebhookEmbed {
+        title: title.to_string(),
+        description,
+        fields,
+        ..Default::default()
+    })
+}
+
+fn mod_link(mod_id: i64, name: Option<String>, url: Option<String>) -> String {
+    match (name, url) {
+        (Some(name), Some(url)) => format!("[{}]({})", name, url),
+        (Some(name), None) => name,
+        _ => format!("Hidden mod ({})", mod_id),
+    }
+}
@@ -0,0 +1,340 @@
+use serde::Deserialize;
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use tracing::warn;
+
+use std::path::Path;
+
+use crate::embed::EmbedTemplate;
+use crate::event::EventKind;
+use crate::filter::{LobbyFilter, Pattern};
+
+/// Environment variable that held the webhook before Discord targets existed
+const LEGACY_WEBHOOK: &str = "DISCORD_WEBHOOK";
+/// Environment variable that held words excluded from lobby names before Discord targets existed
+const LEGACY_NAME_FILTER: &str = "SERVER_NAME_FILTER";
+
+/// Settings read from the TOML config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub discord: Option<DiscordSettings>,
+    /// Endpoints receiving signed POSTs about lobby events
+    #[serde(default)]
+    pub webhooks: Vec<WebhookSubscriber>,
+    #[serde(default)]
+    pub web: WebSettings,
+}
+
+/// Settings of the web server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSettings {
+    /// Address the web server is reachable at, e.g. `https://drg.example.com`. Hosts can only opt
+    /// out if it is set, as Steam sends them back to it after signing in.
+    pub public_url: Option<String>,
+    /// Steam's OpenID 2.0 provider, which hosts opting out sign in with
+    #[serde(default = "default_steam_openid")]
+    pub steam_openid_endpoint: String,
+}
+
+impl Default for WebSettings {
+    fn default() -> Self {
+        Self {
+            public_url: None,
+            steam_openid_endpoint: default_steam_openid(),
+        }
+    }
+}
+
+fn default_steam_openid() -> String {
+    "https://steamcommunity.com/openid/login".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSubscriber {
+    /// Unique name identifying the subscriber's deliveries in the database
+    pub name: String,
+    pub url: String,
+    /// Kinds of events delivered
+    pub events: Vec<EventKind>,
+    /// Environment variable holding the secret requests are signed with
+    pub secret_env: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordSettings {
+    #[serde(default)]
+    pub targets: Vec<DiscordTarget>,
+    #[serde(default)]
+    pub reports: Vec<ReportSettings>,
+}
+
+/// A statistics report posted once per period
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportSettings {
+    /// Unique name identifying the posted reports in the database
+    pub name: String,
+    /// Environment variable holding the webhook the report is posted to
+    pub webhook_env: String,
+    pub period: ReportPeriod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportPeriod {
+    /// The previous UTC day
+    Daily,
+    /// The previous week, Monday to Sunday in UTC
+    Weekly,
+}
+
+/// A channel lobbies are posted to. Each target tracks its own messages, so the same lobby can be
+/// posted to several targets.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordTarget {
+    /// Unique name identifying the target's messages in the database
+    pub name: String,
+    /// Environment variable holding the webhook of Discord and JSON sinks
+    #[serde(default)]
+    pub webhook_env: String,
+    /// Service the messages are delivered to
+    #[serde(default)]
+    pub sink: SinkSettings,
+    #[serde(default)]
+    pub filter: LobbyFilter,
+    /// Messages are only edited when their content changes, or after this many minutes without
+    /// an edit if set
+    pub heartbeat_minutes: Option<i64>,
+    /// What happens to the message of a lobby once it is gone
+    #[serde(default)]
+    pub on_close: ClosedBehavior,
+    /// Layout of the lobby embeds
+    #[serde(default)]
+    pub embed: EmbedTemplate,
+    /// Each lobby gets a thread its changes are posted in. Discord webhooks need to belong to a
+    /// forum channel.
+    #[serde(default)]
+    pub forum: bool,
+    /// Keep a single message listing all matching lobbies, in addition to one message per lobby
+    #[serde(default)]
+    pub status_board: bool,
+    /// Who is pinged when a lobby is first posted, only supported by Discord sinks
+    #[serde(default)]
+    pub mentions: Mentions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SinkSettings {
+    /// Discord webhook
+    #[default]
+    Discord,
+    /// Slack channel, posted to with the bot token in `SLACK_BOT_TOKEN`
+    Slack {
+        /// Channel ID
+        channel: String,
+        #[serde(default = "default_slack_api")]
+        api_url: String,
+    },
+    /// Matrix room, posted to with the access token in `MATRIX_ACCESS_TOKEN`
+    Matrix {
+        /// Base URL of the homeserver, e.g. `https://matrix.org`
+        homeserver: String,
+        room_id: String,
+    },
+    /// Posts, edits and deletions sent as JSON documents to the webhook in `webhook_env`
+    Json,
+}
+
+fn default_slack_api() -> String {
+    "https://slack.com/api".to_string()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mentions {
+    /// Role IDs
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// User IDs
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Minimum time between two pings of the same role or user
+    #[serde(default)]
+    pub cooldown_minutes: i64,
+    /// Minimum time between two pings caused by lobbies of the same host
+    #[serde(default)]
+    pub host_cooldown_minutes: i64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClosedBehavior {
+    /// Delete the message
+    #[default]
+    Delete,
+    /// Edit the message into a summary of the closed lobby, deleting it after
+    /// `delete_after_minutes` or keeping it forever if unset
+    Close { delete_after_minutes: Option<i64> },
+}
+
+impl Settings {
+    /// Loads settings from `path`, falling back to defaults if the file does not exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut settings = if path.exists() {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))?
+        } else {
+            warn!("config file {} not found, using defaults", path.display());
+            Self::default()
+        };
+        settings.apply_legacy_env(
+            std::env::var_os(LEGACY_WEBHOOK).is_some(),
+            std::env::var(LEGACY_NAME_FILTER).ok().as_deref(),
+        )?;
+
+        if let Some(name) = settings
+            .webhooks
+            .iter()
+            .map(|w| &w.name)
+            .duplicates()
+            .next()
+        {
+            bail!("duplicate webhook name {:?}", name);
+        }
+        if let Some(discord) = &settings.discord {
+            if let Some(name) = discord.targets.iter().map(|t| &t.name).duplicates().next() {
+                bail!("duplicate Discord target name {:?}", name);
+            }
+            if let Some(name) = discord.reports.iter().map(|r| &r.name).duplicates().next() {
+                bail!("duplicate Discord report name {:?}", name);
+            }
+            for target in &discord.targets {
+                let uses_webhook =
+                    matches!(target.sink, SinkSettings::Discord | SinkSettings::Json);
+                if uses_webhook && target.webhook_env.is_empty() {
+                    bail!("target {:?} requires a webhook_env", target.name);
+                }
+                let mentions = &target.mentions;
+                let pings = !mentions.roles.is_empty() || !mentions.users.is_empty();
+                if pings && !matches!(target.sink, SinkSettings::Discord) {
+                    bail!(
+                        "mentions of target {:?} require a Discord sink",
+                        target.name
+                    );
+                }
+                target.embed.validate().with_context(|| {
+                    format!("invalid embed of Discord target {:?}", target.name)
+                })?;
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// Carries over the environment variables that configured the single Discord channel before
+    /// targets existed. `DISCORD_WEBHOOK` stands in for a missing `[discord]` section with a
+    /// target named "default", and the words of `SERVER_NAME_FILTER` are excluded from that
+    /// target's lobby names.
+    fn apply_legacy_env(&mut self, webhook: bool, name_filter: Option<&str>) -> Result<()> {
+        if self.discord.is_none() && webhook {
+            warn!(
+                "{} is deprecated, configure a Discord target named \"default\" with webhook_env = \"{}\" instead",
+                LEGACY_WEBHOOK, LEGACY_WEBHOOK
+            );
+            self.discord = Some(DiscordSettings {
+                targets: vec![DiscordTarget {
+                    name: "default".to_string(),
+                    webhook_env: LEGACY_WEBHOOK.to_string(),
+                    sink: SinkSettings::default(),
+                    filter: LobbyFilter::default(),
+                    heartbeat_minutes: None,
+                    on_close: ClosedBehavior::default(),
+                    embed: EmbedTemplate::default(),
+                    forum: false,
+                    status_board: false,
+                    mentions: Mentions::default(),
+                }],
+                reports: vec![],
+            });
+        }
+        let Some(name_filter) = name_filter else {
+            return Ok(());
+        };
+        let Some(target) = self
+            .discord
+            .as_mut()
+            .and_then(|d| d.targets.iter_mut().find(|t| t.name == "default"))
+        else {
+            bail!(
+                "{} is no longer supported, move its words to name_exclude of a Discord target",
+                LEGACY_NAME_FILTER
+            );
+        };
+        warn!(
+            "{} is deprecated, move its words to name_exclude of the Discord target \"default\"",
+            LEGACY_NAME_FILTER
+        );
+        target
+            .filter
+            .name_exclude
+            .extend(name_filter.split_whitespace().map(Pattern::containing));
+        Ok(())
+    }
+
+    pub fn discord(&self) -> Result<&DiscordSettings> {
+        self.discord
+            .as_ref()
+            .context("Discord integration requires a [discord] section in the config file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_webhook_becomes_the_default_target() {
+        let mut settings = Settings::default();
+        settings
+            .apply_legacy_env(true, Some("modded  Private"))
+            .unwrap();
+
+        let target = &settings.discord().unwrap().targets[0];
+        assert_eq!(target.name, "default");
+        assert_eq!(target.webhook_env, "DISCORD_WEBHOOK");
+        let excluded = &target.filter.name_exclude;
+        assert_eq!(excluded.len(), 2);
+        assert!(excluded[1].0.is_match("PRIVATE lobby"));
+    }
+
+    #[test]
+    fn configured_targets_win_over_the_legacy_webhook() {
+        let mut settings: Settings = toml::from_str(
+            r#"
+            [[discord.targets]]
+            name = "vanilla"
+            webhook_env = "DISCORD_WEBHOOK_VANILLA"
+            "#,
+        )
+        .unwrap();
+        settings.apply_legacy_env(true, None).unwrap();
+        assert_eq!(settings.discord().unwrap().targets.len(), 1);
+
+        // without a default target the name filter has nowhere to go
+        assert!(settings.apply_legacy_env(true, Some("modded")).is_err());
+    }
+
+    #[test]
+    fn name_filter_words_are_literal() {
+        assert!(Pattern::containing("a.b").0.is_match("A.B lobby"));
+        assert!(!Pattern::containing("a.b").0.is_match("axb lobby"));
+    }
+}
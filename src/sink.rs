@@ -0,0 +1,287 @@
+//! Delivery of rendered lobby messages to the chat service of a target
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{bail, Context, Result};
+use tracing::warn;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::discord::{DiscordSink, WebhookBody};
+use crate::matrix::MatrixSink;
+use crate::ratelimit::{self, RateLimiter};
+use crate::settings::{DiscordTarget, SinkSettings};
+use crate::slack::SlackSink;
+
+/// Outcome of sending a message
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+    /// `id` identifies the message for later edits. `thread_id` is the thread it lives in, which
+    /// is the message itself if it opened a thread.
+    Sent {
+        id: String,
+        thread_id: Option<String>,
+    },
+    /// The message to edit no longer exists
+    Gone,
+    /// The service refused the message, the reason is already logged
+    Failed,
+}
+
+/// A chat service lobby messages are delivered to. Messages are rendered as [`WebhookBody`] and
+/// each sink converts them to what its service understands.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Edits `message_id` if set, otherwise posts a new message. A new message is posted in
+    /// `thread_id` if set, and opens a thread of its own if `body.thread_name` is set.
+    async fn send(
+        &self,
+        message_id: Option<&str>,
+        thread_id: Option<&str>,
+        body: &WebhookBody,
+    ) -> Result<Delivery>;
+
+    async fn delete(&self, message_id: &str, thread_id: Option<&str>) -> Result<()>;
+}
+
+/// Sink delivering the messages of `target`
+pub fn for_target<'a>(
+    target: &'a DiscordTarget,
+    limiter: &'a RateLimiter,
+    dry_run: bool,
+) -> Result<Box<dyn Sink + 'a>> {
+    Ok(match &target.sink {
+        SinkSettings::Discord => Box::new(DiscordSink::new(
+            limiter,
+            token(&target.webhook_env, "Discord", dry_run)?,
+        )),
+        SinkSettings::Slack { channel, api_url } => Box::new(SlackSink::new(
+            limiter,
+            api_url,
+            channel,
+            token("SLACK_BOT_TOKEN", "Slack", dry_run)?,
+        )),
+        SinkSettings::Matrix {
+            homeserver,
+            room_id,
+        } => Box::new(MatrixSink::new(
+            limiter,
+            homeserver,
+            room_id,
+            token("MATRIX_ACCESS_TOKEN", "Matrix", dry_run)?,
+        )),
+        SinkSettings::Json => Box::new(JsonSink::new(
+            limiter,
+            token(&target.webhook_env, "JSON", dry_run)?,
+        )),
+    })
+}
+
+/// Token or webhook for `service` from the environment variable `var`. Dry runs send nothing, so
+/// they do without.
+fn token(var: &str, service: &str, dry_run: bool) -> Result<String> {
+    match std::env::var(var) {
+        Err(_) if dry_run => Ok(String::new()),
+        res => res.with_context(|| format!("{} targets require {}", service, var)),
+    }
+}
+
+/// Identifier that is unique within this process, for services that let the client pick IDs
+pub fn unique_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{:x}-{}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Rewrites the markdown links `[text](url)` of `text` with `link(text, url)`
+pub fn replace_links(text: &str, link: impl Fn(&str, &str) -> String) -> String {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    let regex = LINK.get_or_init(|| Regex::new(r"\[([^\]]*)\]\(([^)\s]+)\)").unwrap());
+    regex
+        .replace_all(text, |c: &regex::Captures| link(&c[1], &c[2]))
+        .into_owned()
+}
+
+/// Document POSTed by [`JsonSink`]
+#[derive(Debug, Serialize)]
+struct JsonEvent<'a> {
+    /// `post`, `edit` or `delete`
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a WebhookBody>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct JsonReply {
+    id: Option<String>,
+}
+
+/// POSTs every post, edit and deletion as a JSON document to a webhook. The endpoint may answer
+/// a post with `{"id": "..."}` to choose the ID later edits refer to, otherwise one is generated.
+/// Edits answered with 404 or 410 are treated as deleted messages.
+pub struct JsonSink<'a> {
+    limiter: &'a RateLimiter,
+    url: String,
+}
+
+impl<'a> JsonSink<'a> {
+    pub fn new(limiter: &'a RateLimiter, url: String) -> Self {
+        Self { limiter, url }
+    }
+
+    async fn post(&self, event: &JsonEvent<'_>) -> Result<reqwest::Response> {
+        let request = self.limiter.client().post(&self.url).json(event);
+        self.limiter
+            .send(&ratelimit::route("POST", &self.url), request)
+            .await
+    }
+}
+
+#[async_trait]
+impl Sink for JsonSink<'_> {
+    async fn send(
+        &self,
+        message_id: Option<&str>,
+        thread_id: Option<&str>,
+        body: &WebhookBody,
+    ) -> Result<Delivery> {
+        let res = self
+            .post(&JsonEvent {
+                action: if message_id.is_some() { "edit" } else { "post" },
+                id: message_id,
+                thread_id,
+                message: Some(body),
+            })
+            .await?;
+
+        let status = res.status();
+        if message_id.is_some() && matches!(status.as_u16(), 404 | 410) {
+            return Ok(Delivery::Gone);
+        }
+        if !status.is_success() {
+            warn!("Received error from endpoint: {}", status);
+            return Ok(Delivery::Failed);
+        }
+        let reply: JsonReply = res.json().await.unwrap_or_default();
+        let id = match message_id {
+            Some(id) => id.to_string(),
+            None => reply.id.unwrap_or_else(unique_id),
+        };
+        let thread_id = match thread_id {
+            Some(thread_id) => Some(thread_id.to_string()),
+            None => body.thread_name.as_ref().map(|_| id.clone()),
+        };
+        Ok(Delivery::Sent { id, thread_id })
+    }
+
+    async fn delete(&self, message_id: &str, thread_id: Option<&str>) -> Result<()> {
+        let res = self
+            .post(&JsonEvent {
+                action: "delete",
+                id: Some(message_id),
+                thread_id,
+                message: None,
+            })
+            .await?;
+        if !res.status().is_success() && !matches!(res.status().as_u16(), 404 | 410) {
+            bail!("failed to delete {}: {}", message_id, res.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_service, FakeResponse};
+
+    const OK: FakeResponse = FakeResponse::new(200, "");
+    const WITH_ID: FakeResponse = FakeResponse::new(200, r#"{"id":"abc"}"#);
+    const NOT_FOUND: FakeResponse = FakeResponse::new(404, "");
+
+    fn body() -> WebhookBody {
+        WebhookBody {
+            content: Some("hello".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replaces_links() {
+        assert_eq!(
+            replace_links("[a](https://a) and [b](https://b)", |t, u| format!(
+                "<{}|{}>",
+                u, t
+            )),
+            "<https://a|a> and <https://b|b>"
+        );
+        assert_eq!(
+            replace_links("[not a link]", |_, _| String::new()),
+            "[not a link]"
+        );
+    }
+
+    #[tokio::test]
+    async fn json_posts_events() {
+        let (server, limiter, url) = fake_service(vec![WITH_ID, OK]).await;
+        let sink = JsonSink::new(&limiter, url);
+
+        let sent = sink.send(None, None, &body()).await.unwrap();
+        assert_eq!(
+            sent,
+            Delivery::Sent {
+                id: "abc".to_string(),
+                thread_id: None
+            }
+        );
+        sink.delete("abc", None).await.unwrap();
+
+        let received = server.received().await;
+        let events: Vec<serde_json::Value> = received
+            .iter()
+            .map(|r| serde_json::from_str(&r.body).unwrap())
+            .collect();
+        assert_eq!(events[0]["action"], "post");
+        assert_eq!(events[0]["message"]["content"], "hello");
+        assert_eq!(events[1]["action"], "delete");
+        assert_eq!(events[1]["id"], "abc");
+    }
+
+    #[tokio::test]
+    async fn json_generates_ids_and_threads() {
+        let (_server, limiter, url) = fake_service(vec![OK]).await;
+        let sink = JsonSink::new(&limiter, url);
+
+        let data = WebhookBody {
+            thread_name: Some("lobby".to_string()),
+            ..body()
+        };
+        let Delivery::Sent { id, thread_id } = sink.send(None, None, &data).await.unwrap() else {
+            panic!("not sent");
+        };
+        assert!(!id.is_empty());
+        assert_eq!(thread_id, Some(id));
+    }
+
+    #[tokio::test]
+    async fn json_edit_of_missing_message_is_gone() {
+        let (server, limiter, url) = fake_service(vec![NOT_FOUND]).await;
+        let sink = JsonSink::new(&limiter, url);
+
+        let res = sink.send(Some("abc"), None, &body()).await.unwrap();
+        assert_eq!(res, Delivery::Gone);
+        let received = server.received().await;
+        assert!(received[0].body.contains(r#""action":"edit""#));
+    }
+}
@@ -0,0 +1,306 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use crate::discord::{truncate_chars, WebhookBody, WebhookEmbed};
+use crate::ratelimit::RateLimiter;
+use crate::sink::{replace_links, Delivery, Sink};
+
+// Block Kit rejects messages exceeding any of these
+const HEADER_LIMIT: usize = 150;
+const TEXT_LIMIT: usize = 3000;
+const FIELD_LIMIT: usize = 2000;
+const FIELDS_PER_SECTION: usize = 10;
+const BLOCK_LIMIT: usize = 50;
+
+#[derive(Debug, Deserialize)]
+struct SlackResponse {
+    ok: bool,
+    ts: Option<String>,
+    error: Option<String>,
+}
+
+/// Posts to a Slack channel through the Web API (`chat.postMessage`, `chat.update` and
+/// `chat.delete`) with a bot token, as incoming webhooks cannot edit their messages
+pub struct SlackSink<'a> {
+    limiter: &'a RateLimiter,
+    api_url: &'a str,
+    channel: &'a str,
+    token: String,
+}
+
+impl<'a> SlackSink<'a> {
+    pub fn new(
+        limiter: &'a RateLimiter,
+        api_url: &'a str,
+        channel: &'a str,
+        token: String,
+    ) -> Self {
+        Self {
+            limiter,
+            api_url,
+            channel,
+            token,
+        }
+    }
+
+    async fn call(&self, method: &str, data: &Value) -> Result<SlackResponse> {
+        let url = format!("{}/{}", self.api_url.trim_end_matches('/'), method);
+        let request = self
+            .limiter
+            .client()
+            .post(&url)
+            .bearer_auth(&self.token)
+            .json(data);
+        Ok(self
+            .limiter
+            .send(&format!("POST {}", url), request)
+            .await?
+            .json()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl Sink for SlackSink<'_> {
+    async fn send(
+        &self,
+        message_id: Option<&str>,
+        thread_id: Option<&str>,
+        body: &WebhookBody,
+    ) -> Result<Delivery> {
+        let mut data = json!({
+            "channel": self.channel,
+            "text": fallback_text(body),
+            "blocks": blocks(body),
+            "unfurl_links": false,
+        });
+        if let Some(avatar) = &body.avatar_url {
+            data["icon_url"] = avatar.as_str().into();
+        }
+        let res = match message_id {
+            Some(ts) => {
+                data["ts"] = ts.into();
+                self.call("chat.update", &data).await?
+            }
+            None => {
+                if let Some(thread_ts) = thread_id {
+                    data["thread_ts"] = thread_ts.into();
+                }
+                self.call("chat.postMessage", &data).await?
+            }
+        };
+
+        match res {
+            SlackResponse {
+                ok: true,
+                ts: Some(ts),
+                ..
+            } => {
+                let id = message_id.map(str::to_string).unwrap_or(ts);
+                // replies to the message form its thread
+                let thread_id = match thread_id {
+                    Some(thread_id) => Some(thread_id.to_string()),
+                    None => body.thread_name.as_ref().map(|_| id.clone()),
+                };
+                Ok(Delivery::Sent { id, thread_id })
+            }
+            SlackResponse { error, .. } => {
+                let error = error.unwrap_or_default();
+                if message_id.is_some() && error == "message_not_found" {
+                    return Ok(Delivery::Gone);
+                }
+                warn!("Received error from Slack: {}", error);
+                Ok(Delivery::Failed)
+            }
+        }
+    }
+
+    async fn delete(&self, message_id: &str, _thread_id: Option<&str>) -> Result<()> {
+        let res = self
+            .call(
+                "chat.delete",
+                &json!({ "channel": self.channel, "ts": message_id }),
+            )
+            .await?;
+        match res.error.as_deref() {
+            _ if res.ok => Ok(()),
+            Some("message_not_found") => Ok(()),
+            error => bail!(
+                "failed to delete {}: {}",
+                message_id,
+                error.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Converts Discord markdown to Slack's mrkdwn
+fn mrkdwn(text: &str) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    replace_links(&escaped, |text, url| format!("<{}|{}>", url, text)).replace("**", "*")
+}
+
+/// Shown in notifications and by clients that cannot display blocks
+fn fallback_text(body: &WebhookBody) -> String {
+    body.content
+        .iter()
+        .chain(body.embeds.iter().map(|e| &e.title))
+        .find(|t| !t.is_empty())
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn section(text: &str) -> Value {
+    json!({
+        "type": "section",
+        "text": { "type": "mrkdwn", "text": truncate_chars(&mrkdwn(text), TEXT_LIMIT) },
+    })
+}
+
+fn embed_blocks(embed: &WebhookEmbed) -> Vec<Value> {
+    let mut blocks = vec![];
+    if !embed.title.is_empty() {
+        blocks.push(json!({
+            "type": "header",
+            "text": { "type": "plain_text", "text": truncate_chars(&embed.title, HEADER_LIMIT) },
+        }));
+    }
+    if let Some(author) = &embed.author {
+        let mut elements = vec![];
+        if let Some(icon) = &author.icon_url {
+            elements.push(json!({ "type": "image", "image_url": icon, "alt_text": author.name }));
+        }
+        let name = match &author.url {
+            Some(url) => format!("[{}]({})", author.name, url),
+            None => author.name.clone(),
+        };
+        elements.push(json!({ "type": "mrkdwn", "text": mrkdwn(&name) }));
+        blocks.push(json!({ "type": "context", "elements": elements }));
+    }
+    if !embed.description.is_empty() {
+        blocks.push(section(&embed.description));
+    }
+    for fields in embed.fields.chunks(FIELDS_PER_SECTION) {
+        let fields: Vec<Value> = fields
+            .iter()
+            .map(|f| {
+                let text = format!("*{}*\n{}", mrkdwn(&f.name), mrkdwn(&f.value));
+                json!({ "type": "mrkdwn", "text": truncate_chars(&text, FIELD_LIMIT) })
+            })
+            .collect();
+        blocks.push(json!({ "type": "section", "fields": fields }));
+    }
+    blocks
+}
+
+/// Block Kit layout of a message: the content, then each embed as a header, its author, its
+/// description and its fields, separated by dividers
+fn blocks(body: &WebhookBody) -> Vec<Value> {
+    let mut blocks = vec![];
+    if let Some(content) = body.content.as_ref().filter(|c| !c.is_empty()) {
+        blocks.push(section(content));
+    }
+    for embed in &body.embeds {
+        if !blocks.is_empty() {
+            blocks.push(json!({ "type": "divider" }));
+        }
+        blocks.extend(embed_blocks(embed));
+    }
+    blocks.truncate(BLOCK_LIMIT);
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::WebhookField;
+    use crate::testutil::{fake_service, FakeResponse};
+
+    const POSTED: FakeResponse = FakeResponse::new(
+        200,
+        r#"{"ok":true,"channel":"C1","ts":"1700000000.000100"}"#,
+    );
+    const NOT_FOUND: FakeResponse =
+        FakeResponse::new(200, r#"{"ok":false,"error":"message_not_found"}"#);
+
+    fn lobby_body() -> WebhookBody {
+        WebhookBody {
+            embeds: vec![WebhookEmbed {
+                title: "Rock and Stone".to_string(),
+                description: "**Hazard 5** with [Mod](https://mod.io/m/1)".to_string(),
+                fields: vec![WebhookField {
+                    name: "Region".to_string(),
+                    value: "Europe <3".to_string(),
+                    inline: true,
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn converts_markdown() {
+        assert_eq!(
+            mrkdwn("**Hazard 5** with [Mod](https://mod.io/m/1) <3"),
+            "*Hazard 5* with <https://mod.io/m/1|Mod> &lt;3"
+        );
+    }
+
+    #[test]
+    fn splits_fields_into_sections() {
+        let mut body = lobby_body();
+        body.embeds[0].fields = vec![WebhookField::default(); 25];
+        let blocks = blocks(&body);
+        let sections: Vec<usize> = blocks
+            .iter()
+            .filter_map(|b| b["fields"].as_array().map(Vec::len))
+            .collect();
+        assert_eq!(sections, [10, 10, 5]);
+    }
+
+    #[tokio::test]
+    async fn posts_and_edits() {
+        let (server, limiter, url) = fake_service(vec![POSTED]).await;
+        let sink = SlackSink::new(&limiter, &url, "C1", "xoxb-token".to_string());
+
+        let sent = sink.send(None, None, &lobby_body()).await.unwrap();
+        assert_eq!(
+            sent,
+            Delivery::Sent {
+                id: "1700000000.000100".to_string(),
+                thread_id: None
+            }
+        );
+        sink.send(Some("1700000000.000100"), None, &lobby_body())
+            .await
+            .unwrap();
+
+        let received = server.received().await;
+        let paths: Vec<&str> = received.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/chat.postMessage", "/chat.update"]);
+        let post: Value = serde_json::from_str(&received[0].body).unwrap();
+        assert_eq!(post["channel"], "C1");
+        assert_eq!(post["text"], "Rock and Stone");
+        assert_eq!(post["blocks"][0]["type"], "header");
+        let update: Value = serde_json::from_str(&received[1].body).unwrap();
+        assert_eq!(update["ts"], "1700000000.000100");
+    }
+
+    #[tokio::test]
+    async fn missing_message_is_gone() {
+        let (_server, limiter, url) = fake_service(vec![NOT_FOUND]).await;
+        let sink = SlackSink::new(&limiter, &url, "C1", "xoxb-token".to_string());
+
+        let res = sink.send(Some("1.2"), None, &lobby_body()).await.unwrap();
+        assert_eq!(res, Delivery::Gone);
+        sink.delete("1.2", None).await.unwrap();
+    }
+}
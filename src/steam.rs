@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::Result;
+use itertools::Itertools;
+use tracing::{info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::discord::parse_response;
+
+/// How long a cached player summary is used before it is fetched again
+const CACHE_TTL_SECONDS: i64 = 60 * 60 * 6;
+/// Most steamids `GetPlayerSummaries` accepts per call
+const BATCH_SIZE: usize = 100;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SteamPlayerRequest {
+    pub response: SteamPlayerResponse,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SteamPlayerResponse {
+    pub players: Vec<SteamPlayer>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SteamPlayer {
+    pub steamid: String,
+    pub communityvisibilitystate: i64,
+    pub profilestate: Option<i64>,
+    pub personaname: String,
+    pub profileurl: String,
+    pub avatar: String,
+    pub avatarmedium: String,
+    pub avatarfull: String,
+    pub avatarhash: String,
+    pub personastate: i64,
+    pub primaryclanid: Option<String>,
+    pub timecreated: Option<i64>,
+    pub personastateflags: Option<i64>,
+}
+
+/// Player summary as cached in the `steam_player` table
+#[derive(Debug, Clone, Serialize)]
+pub struct Player {
+    pub personaname: String,
+    pub avatar: String,
+    pub avatarfull: String,
+    pub updated: i64,
+}
+
+/// Returns cached summaries of `steamids` without contacting Steam, regardless of their age
+pub async fn cached_players(
+    pool: &SqlitePool,
+    steamids: &[&str],
+) -> Result<HashMap<String, Player>> {
+    let ids = serde_json::to_string(steamids)?;
+    Ok(sqlx::query!(
+        r#"SELECT steamid, personaname, avatar, avatarfull, updated
+            FROM steam_player
+            WHERE steamid IN (SELECT value FROM json_each(?))
+        "#,
+        ids,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        (
+            r.steamid,
+            Player {
+                personaname: r.personaname,
+                avatar: r.avatar,
+                avatarfull: r.avatarfull,
+                updated: r.updated,
+            },
+        )
+    })
+    .collect())
+}
+
+/// Returns summaries of `steamids`, first refreshing missing or expired ones from Steam in batches.
+/// If Steam cannot be reached the stale cache is used, so players may be missing from the result.
+#[tracing::instrument(skip_all)]
+pub async fn get_players(pool: &SqlitePool, steamids: &[&str]) -> Result<HashMap<String, Player>> {
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+
+    let cached = cached_players(pool, steamids).await?;
+    let fresh = fresh_ids(pool, steamids, now - CACHE_TTL_SECONDS).await?;
+    let stale: Vec<&str> = steamids
+        .iter()
+        .copied()
+        .unique()
+        .filter(|id| !fresh.contains(*id))
+        .collect();
+
+    if stale.is_empty() {
+        return Ok(cached);
+    }
+
+    let Some(key) = std::env::var("STEAM_WEB_KEY")
+        .ok()
+        .filter(|k| !k.is_empty())
+    else {
+        warn!(
+            "STEAM_WEB_KEY not set, not refreshing {} players",
+            stale.len()
+        );
+        return Ok(cached);
+    };
+
+    info!("refreshing {} players", stale.len());
+    for batch in stale.chunks(BATCH_SIZE) {
+        if let Err(e) = refresh_players(pool, &key, batch, now).await {
+            warn!("failed to refresh players: {:#}", e);
+        }
+    }
+
+    cached_players(pool, steamids).await
+}
+
+/// IDs among `steamids` that were fetched after `since`, whether Steam knew them or not
+async fn fresh_ids(pool: &SqlitePool, steamids: &[&str], since: i64) -> Result<HashSet<String>> {
+    let ids = serde_json::to_string(steamids)?;
+    Ok(sqlx::query_scalar!(
+        r#"SELECT steamid AS "steamid!"
+            FROM steam_player
+            WHERE steamid IN (SELECT value FROM json_each(?)) AND updated > ?
+            UNION
+            SELECT steamid
+            FROM steam_player_miss
+            WHERE steamid IN (SELECT value FROM json_each(?)) AND checked > ?
+        "#,
+        ids,
+        since,
+        ids,
+        since,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect())
+}
+
+async fn refresh_players(pool: &SqlitePool, key: &str, steamids: &[&str], now: i64) -> Result<()> {
+    let result: SteamPlayerRequest = parse_response(
+        reqwest::Client::new()
+            .get(format!(
+                "https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v0002/?key={}&steamids={}",
+                key,
+                steamids.join(",")
+            ))
+            .send()
+            .await
+            // the URL carries the key
+            .map_err(|e| e.without_url())?,
+    )
+    .await?;
+
+    let found: HashSet<&str> = result
+        .response
+        .players
+        .iter()
+        .map(|p| p.steamid.as_str())
+        .collect();
+    for steamid in steamids.iter().filter(|id| !found.contains(*id)) {
+        sqlx::query!(
+            r#"INSERT INTO steam_player_miss (steamid, checked) VALUES (?, ?)
+                ON CONFLICT(steamid) DO UPDATE SET checked = excluded.checked
+            "#,
+            steamid,
+            now,
+        )
+        .execute(pool)
+        .await?;
+    }
+    for player in result.response.players {
+        sqlx::query!(
+            r#"INSERT INTO steam_player (steamid, personaname, avatar, avatarfull, updated)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(steamid) DO UPDATE SET
+                    personaname = excluded.personaname,
+                    avatar = excluded.avatar,
+                    avatarfull = excluded.avatarfull,
+                    updated = excluded.updated
+            "#,
+            player.steamid,
+            player.personaname,
+            player.avatar,
+            player.avatarfull,
+            now,
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::memory_pool;
+
+    #[tokio::test]
+    async fn known_and_missing_players_stay_fresh_until_they_expire() {
+        let pool = memory_pool().await;
+        sqlx::query!(
+            "INSERT INTO steam_player (steamid, personaname, avatar, avatarfull, updated) VALUES ('1', 'a', '', '', 100), ('2', 'b', '', '', 10)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO steam_player_miss (steamid, checked) VALUES ('3', 100), ('4', 10)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let fresh = fresh_ids(&pool, &["1", "2", "3", "4", "5"], 50)
+            .await
+            .unwrap();
+        assert_eq!(fresh, HashSet::from(["1".to_string(), "3".to_string()]));
+    }
+}
@@ -0,0 +1,175 @@
+//! Local stand-ins for the HTTP services and the database the bot talks to
+
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::ratelimit::RateLimiter;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FakeResponse {
+    pub status: u16,
+    pub headers: &'static [(&'static str, &'static str)],
+    pub body: &'static str,
+}
+
+impl FakeResponse {
+    /// Response without extra headers
+    pub const fn new(status: u16, body: &'static str) -> Self {
+        Self {
+            status,
+            headers: &[],
+            body,
+        }
+    }
+}
+
+/// A request received by a [`FakeServer`]
+#[derive(Debug, Clone)]
+pub struct FakeRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl FakeRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// HTTP server answering requests with a scripted sequence of responses, repeating the last one
+/// once the sequence is exhausted
+pub struct FakeServer {
+    url: String,
+    count: Arc<AtomicUsize>,
+    received: Arc<Mutex<Vec<FakeRequest>>>,
+}
+
+impl FakeServer {
+    pub async fn start(responses: Vec<FakeResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(Mutex::new(vec![]));
+
+        let (counter, log) = (count.clone(), received.clone());
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let i = counter.fetch_add(1, Ordering::SeqCst);
+                let response = responses[i.min(responses.len() - 1)];
+                let log = log.clone();
+                tokio::spawn(respond(socket, response, log));
+            }
+        });
+
+        Self {
+            url,
+            count,
+            received,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Number of requests received so far
+    pub fn requests(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub async fn received(&self) -> Vec<FakeRequest> {
+        self.received.lock().await.clone()
+    }
+}
+
+/// A [`FakeServer`] answering with `responses`, a limiter that does not retry and the server's URL
+/// for clients to borrow, as most client tests start out
+pub async fn fake_service(responses: Vec<FakeResponse>) -> (FakeServer, RateLimiter, String) {
+    let server = FakeServer::start(responses).await;
+    let url = server.url();
+    (server, RateLimiter::new(0), url)
+}
+
+/// Empty in-memory database with every migration applied. Every connection to `sqlite::memory:`
+/// opens a database of its own, so the pool keeps a single one.
+pub async fn memory_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
+
+/// Records the request before answering it, so requests are logged in the order they were sent
+async fn respond(mut socket: TcpStream, response: FakeResponse, log: Arc<Mutex<Vec<FakeRequest>>>) {
+    let mut buf = vec![];
+    let header_end = loop {
+        let mut chunk = [0; 1024];
+        let n = socket.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if n == 0 {
+            break buf.len();
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let length = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < header_end + length {
+        let mut chunk = [0; 1024];
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+    log.lock().await.push(FakeRequest {
+        method,
+        path,
+        headers,
+        body,
+    });
+
+    let mut out = format!(
+        "HTTP/1.1 {} Fake\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in response.headers {
+        out.push_str(&format!("{name}: {value}\r\n"));
+    }
+    out.push_str("\r\n");
+    out.push_str(response.body);
+    socket.write_all(out.as_bytes()).await.unwrap();
+}
@@ -0,0 +1,295 @@
+//! Notifications for players waiting for a slot in a full lobby
+
+use futures::channel::mpsc::Sender;
+use futures::SinkExt;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::{bail, Context, Result};
+use tracing::{info, warn};
+
+use std::time::{Duration, Instant};
+
+use crate::event::{events_since, Event, EventKind};
+use crate::ratelimit::RateLimiter;
+
+const DISCORD_API: &str = "https://discord.com/api/v10";
+/// Watches that have not fired within this many hours are dropped
+const WATCH_HOURS: i64 = 6;
+/// Only lobbies seen within this many minutes can be watched
+const RECENT_MINUTES: i64 = 10;
+/// How often event streams check for new events. Streams without news get a comment instead,
+/// which keeps proxies from closing them and notices clients that went away.
+const STREAM_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Whether an event of `kind` means a watched lobby can be joined again
+fn notifies(kind: &str) -> bool {
+    kind == EventKind::SlotOpened.as_str() || kind == EventKind::ReturnedToRig.as_str()
+}
+
+/// Whether `lobby_id` was seen recently enough to be watched
+pub async fn is_recent(pool: &SqlitePool, lobby_id: &str, now: i64) -> Result<bool> {
+    let since = now - RECENT_MINUTES * 60;
+    Ok(sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM server WHERE lobby_id = ? AND time > ?) AS "recent!: bool""#,
+        lobby_id,
+        since,
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Starts watching `lobby_id` for `discord_user_id`, from the latest event on. Watching a lobby
+/// again restarts the watch.
+pub async fn add_watch(
+    pool: &SqlitePool,
+    lobby_id: &str,
+    discord_user_id: &str,
+    now: i64,
+) -> Result<()> {
+    sqlx::query!(
+        r#"INSERT INTO lobby_watch (lobby_id, discord_user_id, created_at, after_event_id)
+            VALUES (?, ?, ?, (SELECT IFNULL(MAX(event_id), 0) FROM event))
+            ON CONFLICT(lobby_id, discord_user_id) DO UPDATE SET
+                created_at = excluded.created_at,
+                after_event_id = excluded.after_event_id
+        "#,
+        lobby_id,
+        discord_user_id,
+        now,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+fn dm_content(kind: &str, server_name: &str) -> String {
+    match kind {
+        "slot_opened" => format!("A slot opened in **{}**", server_name),
+        "returned_to_rig" => format!("**{}** returned to the Space Rig", server_name),
+        _ => format!("**{}** closed", server_name),
+    }
+}
+
+/// Sends a DM to every user whose watched lobby had a slot open, returned to the Space Rig or
+/// closed since the watch started, using `DISCORD_BOT_TOKEN`. Watches fire once and are dropped
+/// after [`WATCH_HOURS`] otherwise.
+#[tracing::instrument(skip_all)]
+pub async fn notify_watches(pool: &SqlitePool, limiter: &RateLimiter, now: i64) -> Result<()> {
+    let token = std::env::var("DISCORD_BOT_TOKEN").context("watches require DISCORD_BOT_TOKEN")?;
+
+    let expired_before = now - WATCH_HOURS * 60 * 60;
+    let expired = sqlx::query!(
+        "DELETE FROM lobby_watch WHERE created_at < ?",
+        expired_before
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if expired > 0 {
+        info!("dropped {} expired watches", expired);
+    }
+
+    let due = sqlx::query!(
+        r#"SELECT lobby_id AS "lobby_id!: String",
+            discord_user_id AS "discord_user_id!: String",
+            kind AS "kind!: String",
+            server_name AS "server_name?: String"
+            FROM (SELECT lobby_id,
+                discord_user_id,
+                (SELECT kind
+                    FROM event
+                    WHERE
+                        event.lobby_id = lobby_watch.lobby_id
+                        AND event_id > after_event_id
+                        AND kind IN ('slot_opened', 'returned_to_rig', 'lobby_closed')
+                    ORDER BY event_id
+                    LIMIT 1
+                ) AS kind,
+                (SELECT server_name
+                    FROM server
+                    WHERE server.lobby_id = lobby_watch.lobby_id
+                    ORDER BY time DESC
+                    LIMIT 1
+                ) AS server_name
+                FROM lobby_watch)
+            WHERE kind IS NOT NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for watch in due {
+        let content = dm_content(
+            &watch.kind,
+            watch.server_name.as_deref().unwrap_or(&watch.lobby_id),
+        );
+        // users with closed DMs would fail on every run, so the watch is dropped either way
+        if let Err(e) = send_dm(
+            limiter,
+            DISCORD_API,
+            &token,
+            &watch.discord_user_id,
+            &content,
+        )
+        .await
+        {
+            warn!("failed to notify {}: {:#}", watch.discord_user_id, e);
+        }
+        sqlx::query!(
+            "DELETE FROM lobby_watch WHERE lobby_id = ? AND discord_user_id = ?",
+            watch.lobby_id,
+            watch.discord_user_id,
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct DmChannel {
+    id: String,
+}
+
+/// Opens the DM channel with `user_id` and posts `content` to it
+async fn send_dm(
+    limiter: &RateLimiter,
+    api_url: &str,
+    token: &str,
+    user_id: &str,
+    content: &str,
+) -> Result<()> {
+    let url = format!("{}/users/@me/channels", api_url);
+    let request = limiter
+        .client()
+        .post(&url)
+        .header("Authorization", format!("Bot {}", token))
+        .json(&json!({ "recipient_id": user_id }));
+    let res = limiter.send(&format!("POST {}", url), request).await?;
+    if !res.status().is_success() {
+        bail!("failed to open DM: {}", res.text().await?);
+    }
+    let channel: DmChannel = res.json().await?;
+
+    let url = format!("{}/channels/{}/messages", api_url, channel.id);
+    let request = limiter
+        .client()
+        .post(&url)
+        .header("Authorization", format!("Bot {}", token))
+        .json(&json!({ "content": content }));
+    let res = limiter.send(&format!("POST {}", url), request).await?;
+    if !res.status().is_success() {
+        bail!("failed to send DM: {}", res.text().await?);
+    }
+    Ok(())
+}
+
+/// `event` as a Server-Sent Event named after its kind
+fn sse_message(event: &Event) -> Result<String> {
+    Ok(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.event_id,
+        event.kind,
+        serde_json::to_string(event)?
+    ))
+}
+
+/// Writes the events of `lobby_id` after `after` that a watcher cares about to `tx` as
+/// Server-Sent Events, until the lobby closes or the receiving connection is dropped. Like
+/// watches through Discord, streams end with an `expired` event after [`WATCH_HOURS`].
+pub async fn stream_events(
+    pool: SqlitePool,
+    lobby_id: String,
+    mut after: i64,
+    mut tx: Sender<Vec<u8>>,
+) -> Result<()> {
+    let expires = Instant::now() + Duration::from_secs(WATCH_HOURS as u64 * 60 * 60);
+    let mut interval = tokio::time::interval(STREAM_INTERVAL);
+    loop {
+        interval.tick().await;
+        if Instant::now() >= expires {
+            let _ = tx.send(b"event: expired\ndata: {}\n\n".to_vec()).await;
+            return Ok(());
+        }
+        let mut message = String::new();
+        let mut closed = false;
+        for event in events_since(&pool, after, Some(&lobby_id)).await? {
+            after = event.event_id;
+            if event.kind == EventKind::LobbyClosed.as_str() {
+                message.push_str(&sse_message(&event)?);
+                closed = true;
+                break;
+            }
+            if notifies(&event.kind) {
+                message.push_str(&sse_message(&event)?);
+            }
+        }
+        if message.is_empty() {
+            message.push_str(": keep-alive\n\n");
+        }
+        if tx.send(message.into_bytes()).await.is_err() || closed {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_service, FakeResponse};
+
+    #[test]
+    fn formats_server_sent_events() {
+        let event = Event {
+            event_id: 42,
+            time: 1700000000,
+            lobby_id: "1234".to_string(),
+            kind: "slot_opened".to_string(),
+            data: json!({ "numplayers": 3 }),
+        };
+        assert_eq!(
+            sse_message(&event).unwrap(),
+            "id: 42\nevent: slot_opened\ndata: \
+            {\"event_id\":42,\"time\":1700000000,\"lobby_id\":\"1234\",\"kind\":\"slot_opened\",\"data\":{\"numplayers\":3}}\n\n"
+        );
+        assert!(notifies("returned_to_rig"));
+        assert!(!notifies("player_count_changed"));
+    }
+
+    #[tokio::test]
+    async fn sends_direct_messages() {
+        let (server, limiter, url) = fake_service(vec![
+            FakeResponse::new(200, r#"{"id":"555"}"#),
+            FakeResponse::new(200, r#"{"id":"777"}"#),
+        ])
+        .await;
+
+        let content = dm_content("slot_opened", "Rock and Stone");
+        send_dm(&limiter, &url, "token", "123", &content)
+            .await
+            .unwrap();
+
+        let received = server.received().await;
+        let paths: Vec<&str> = received.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/users/@me/channels", "/channels/555/messages"]);
+        assert_eq!(received[0].header("authorization"), Some("Bot token"));
+        assert_eq!(received[0].body, r#"{"recipient_id":"123"}"#);
+        assert_eq!(
+            received[1].body,
+            r#"{"content":"A slot opened in **Rock and Stone**"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_direct_messages_fail() {
+        let (_server, limiter, url) = fake_service(vec![FakeResponse::new(
+            403,
+            r#"{"message":"Cannot send messages to this user","code":50007}"#,
+        )])
+        .await;
+
+        assert!(send_dm(&limiter, &url, "token", "123", "hi").await.is_err());
+    }
+}
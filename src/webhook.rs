@@ -0,0 +1,533 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use sha2::Sha256;
+use sqlx::sqlite::SqlitePool;
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use std::time::Duration;
+
+use crate::lobby::Mod;
+use crate::settings::WebhookSubscriber;
+
+/// Deliveries are given up after this many failed attempts
+const MAX_ATTEMPTS: i64 = 8;
+/// Wait before the first retry, doubled for every further one
+const BACKOFF_SECONDS: i64 = 60;
+const MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of deliveries attempted per subscriber and run
+const BATCH_SIZE: i64 = 100;
+
+/// Body POSTed to subscribers
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    delivery_id: i64,
+    event_id: i64,
+    time: i64,
+    kind: &'a str,
+    lobby_id: &'a str,
+    /// Details of the change, see the `event` table
+    data: &'a RawValue,
+    /// The lobby as of the event, or as last seen for `lobby_closed`
+    lobby: Option<PayloadLobby>,
+}
+
+#[derive(Debug, Serialize)]
+struct PayloadLobby {
+    time: i64,
+    host_user_id: String,
+    server_name: String,
+    hazard: i64,
+    region: String,
+    numplayers: i64,
+    full: bool,
+    in_mission: bool,
+    classes: String,
+    mods: Vec<Mod>,
+}
+
+/// Hex encoded HMAC-SHA256 of `message`
+fn hmac_hex(secret: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Signature sent in `X-Webhook-Signature`, covering the timestamp so captured requests cannot be
+/// replayed later
+pub fn sign(secret: &[u8], timestamp: i64, body: &str) -> String {
+    format!(
+        "sha256={}",
+        hmac_hex(secret, format!("{}.{}", timestamp, body).as_bytes())
+    )
+}
+
+/// Seconds to wait after the `attempts`th failed attempt
+fn backoff(attempts: i64) -> i64 {
+    let doublings = (attempts - 1).clamp(0, 16);
+    (BACKOFF_SECONDS << doublings).min(MAX_BACKOFF_SECONDS)
+}
+
+/// Queues the events each subscriber wants that happened since the last run, then attempts every
+/// delivery that is due. Failed deliveries are retried with exponential backoff on later runs
+/// and given up after [`MAX_ATTEMPTS`], every attempt is logged in `webhook_attempt`.
+/// Subscribers without their secret are skipped.
+#[tracing::instrument(skip_all)]
+pub async fn deliver_webhooks(
+    pool: &SqlitePool,
+    subscribers: &[WebhookSubscriber],
+    client: &reqwest::Client,
+    now: i64,
+) -> Result<()> {
+    for subscriber in subscribers {
+        let Ok(secret) = std::env::var(&subscriber.secret_env) else {
+            warn!(
+                "skipping webhook {:?}, it requires {}",
+                subscriber.name, subscriber.secret_env
+            );
+            continue;
+        };
+        enqueue(pool, subscriber, now).await?;
+        deliver_pending(pool, subscriber, client, secret.as_bytes(), now).await?;
+    }
+    Ok(())
+}
+
+/// Queues the matching events after the subscriber's cursor, except those of lobbies whose host
+/// opted out. New subscribers start with the events after their first run instead of the whole
+/// history.
+async fn enqueue(pool: &SqlitePool, subscriber: &WebhookSubscriber, now: i64) -> Result<()> {
+    let kinds = serde_json::to_string(
+        &subscriber
+            .events
+            .iter()
+            .map(|k| k.as_str())
+            .collect::<Vec<_>>(),
+    )?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO webhook_subscriber (name, last_event_id) VALUES (?, (SELECT IFNULL(MAX(event_id), 0) FROM event)) ON CONFLICT(name) DO NOTHING",
+        subscriber.name,
+    )
+    .execute(&mut *tx)
+    .await?;
+    let last_event_id = sqlx::query_scalar!(
+        "SELECT last_event_id FROM webhook_subscriber WHERE name = ?",
+        subscriber.name
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let until = sqlx::query_scalar!(r#"SELECT IFNULL(MAX(event_id), 0) AS "id!: i64" FROM event"#)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let queued = sqlx::query!(
+        r#"INSERT INTO webhook_delivery (subscriber, event_id, next_attempt)
+            SELECT ?, event_id, ?
+            FROM event
+            WHERE
+                event_id > ?
+                AND event_id <= ?
+                AND kind IN (SELECT value FROM json_each(?))
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM server
+                    JOIN host_optout USING(host_user_id)
+                    WHERE server.lobby_id = event.lobby_id
+                )
+            ORDER BY event_id
+        "#,
+        subscriber.name,
+        now,
+        last_event_id,
+        until,
+        kinds,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        "UPDATE webhook_subscriber SET last_event_id = ? WHERE name = ?",
+        until,
+        subscriber.name
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    if queued > 0 {
+        info!("queued {} events for webhook {}", queued, subscriber.name);
+    }
+    Ok(())
+}
+
+/// Attempts the due deliveries of `subscriber`, stopping at the first one that gets no answer
+async fn deliver_pending(
+    pool: &SqlitePool,
+    subscriber: &WebhookSubscriber,
+    client: &reqwest::Client,
+    secret: &[u8],
+    now: i64,
+) -> Result<()> {
+    let pending = sqlx::query!(
+        r#"SELECT delivery_id, attempts, event_id, event.time, event.lobby_id, kind, data
+            FROM webhook_delivery
+            JOIN event USING(event_id)
+            WHERE
+                subscriber = ?
+                AND delivered_at IS NULL
+                AND attempts < ?
+                AND next_attempt <= ?
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM server
+                    JOIN host_optout USING(host_user_id)
+                    WHERE server.lobby_id = event.lobby_id
+                )
+            ORDER BY delivery_id
+            LIMIT ?
+        "#,
+        subscriber.name,
+        MAX_ATTEMPTS,
+        now,
+        BATCH_SIZE,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for delivery in pending {
+        let data = RawValue::from_string(delivery.data)?;
+        let body = serde_json::to_string(&Payload {
+            delivery_id: delivery.delivery_id,
+            event_id: delivery.event_id,
+            time: delivery.time,
+            kind: &delivery.kind,
+            lobby_id: &delivery.lobby_id,
+            data: &data,
+            lobby: lobby_at(pool, &delivery.lobby_id, delivery.time).await?,
+        })?;
+
+        let res = send(
+            client,
+            &subscriber.url,
+            secret,
+            delivery.delivery_id,
+            &delivery.kind,
+            body,
+            now,
+        )
+        .await;
+        let attempts = delivery.attempts + 1;
+        let (status, error) = match &res {
+            Ok(status) if status.is_success() => (Some(status.as_u16()), None),
+            Ok(status) => (Some(status.as_u16()), Some(status.to_string())),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        sqlx::query!(
+            "INSERT INTO webhook_attempt (delivery_id, time, status, error) VALUES (?, ?, ?, ?)",
+            delivery.delivery_id,
+            now,
+            status,
+            error,
+        )
+        .execute(pool)
+        .await?;
+
+        let Some(error) = error else {
+            sqlx::query!(
+                "UPDATE webhook_delivery SET attempts = ?, last_status = ?, last_error = NULL, delivered_at = ? WHERE delivery_id = ?",
+                attempts,
+                status,
+                now,
+                delivery.delivery_id,
+            )
+            .execute(pool)
+            .await?;
+            continue;
+        };
+
+        warn!(
+            "delivery {} to webhook {} failed (attempt {}): {}",
+            delivery.delivery_id, subscriber.name, attempts, error
+        );
+        let next_attempt = now + backoff(attempts);
+        sqlx::query!(
+            "UPDATE webhook_delivery SET attempts = ?, last_status = ?, last_error = ?, next_attempt = ? WHERE delivery_id = ?",
+            attempts,
+            status,
+            error,
+            next_attempt,
+            delivery.delivery_id,
+        )
+        .execute(pool)
+        .await?;
+
+        // an endpoint that does not answer would hold up the rest of the batch for a timeout each
+        if res.is_err() {
+            warn!(
+                "postponing further deliveries to webhook {} until the next run",
+                subscriber.name
+            );
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// POSTs a signed delivery, returning the status the subscriber answered with
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &[u8],
+    delivery_id: i64,
+    kind: &str,
+    body: String,
+    now: i64,
+) -> Result<reqwest::StatusCode> {
+    let res = client
+        .post(url)
+        .timeout(TIMEOUT)
+        .header("content-type", "application/json")
+        .header("x-webhook-delivery", delivery_id)
+        .header("x-webhook-event", kind)
+        .header("x-webhook-timestamp", now)
+        .header("x-webhook-signature", sign(secret, now, &body))
+        .body(body)
+        .send()
+        .await?;
+    Ok(res.status())
+}
+
+/// Snapshot of a lobby at `time`, or the last one before it
+async fn lobby_at(pool: &SqlitePool, lobby_id: &str, time: i64) -> Result<Option<PayloadLobby>> {
+    let res = sqlx::query!(
+        r#"SELECT time,
+            host_user_id,
+            server_name,
+            diff,
+            region,
+            numplayers,
+            full,
+            start,
+            classes,
+            (SELECT json_group_array(json_object('id', mod_id, 'category', category, 'name', name, 'url', url)) FROM
+                (SELECT mod_id, server_mod.category, name, url
+                FROM server_mod
+                LEFT JOIN mod USING(mod_id)
+                WHERE
+                    server_mod.time = server.time
+                    AND server_mod.lobby_id = server.lobby_id
+                ORDER BY server_mod.category)
+            ) AS "mods!: String"
+            FROM server
+            WHERE lobby_id = ? AND time <= ?
+            ORDER BY time DESC
+            LIMIT 1
+        "#,
+        lobby_id,
+        time,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    res.map(|r| {
+        Ok(PayloadLobby {
+            time: r.time,
+            host_user_id: r.host_user_id,
+            server_name: r.server_name,
+            hazard: r.diff + 1,
+            region: r.region,
+            numplayers: r.numplayers,
+            full: r.full != 0,
+            in_mission: !r.start.is_empty(),
+            classes: r.classes,
+            mods: serde_json::from_str(&r.mods)?,
+        })
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventKind;
+    use crate::testutil::{memory_pool, FakeResponse, FakeServer};
+
+    fn subscriber(url: &str) -> WebhookSubscriber {
+        WebhookSubscriber {
+            name: "bot".to_string(),
+            url: url.to_string(),
+            events: vec![EventKind::LobbyOpened, EventKind::LobbyClosed],
+            secret_env: "WEBHOOK_SECRET_BOT".to_string(),
+        }
+    }
+
+    async fn add_event(pool: &SqlitePool, lobby_id: &str, kind: &str) {
+        sqlx::query!(
+            "INSERT INTO event (time, lobby_id, kind, data) VALUES (1700000000, ?, ?, '{}')",
+            lobby_id,
+            kind,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn queued(pool: &SqlitePool) -> Vec<i64> {
+        sqlx::query_scalar!("SELECT event_id FROM webhook_delivery ORDER BY delivery_id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hmac_hex(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        let waits: Vec<i64> = (1..=8).map(backoff).collect();
+        assert_eq!(waits, [60, 120, 240, 480, 960, 1920, 3600, 3600]);
+    }
+
+    #[tokio::test]
+    async fn enqueues_wanted_events_after_cursor() {
+        let pool = memory_pool().await;
+        let subscriber = subscriber("http://127.0.0.1:9");
+
+        // new subscribers skip the history
+        add_event(&pool, "1", "lobby_opened").await;
+        enqueue(&pool, &subscriber, 1700000000).await.unwrap();
+        assert!(queued(&pool).await.is_empty());
+
+        add_event(&pool, "2", "lobby_opened").await;
+        add_event(&pool, "2", "mods_changed").await;
+        add_event(&pool, "1", "lobby_closed").await;
+        enqueue(&pool, &subscriber, 1700000000).await.unwrap();
+        enqueue(&pool, &subscriber, 1700000000).await.unwrap();
+        assert_eq!(queued(&pool).await, [2, 4]);
+
+        let cursor =
+            sqlx::query_scalar!("SELECT last_event_id FROM webhook_subscriber WHERE name = 'bot'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(cursor, 4);
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries_with_backoff() {
+        let server =
+            FakeServer::start(vec![FakeResponse::new(500, ""), FakeResponse::new(204, "")]).await;
+        let client = reqwest::Client::new();
+        let pool = memory_pool().await;
+        let subscriber = subscriber(&server.url());
+
+        enqueue(&pool, &subscriber, 1000).await.unwrap();
+        add_event(&pool, "1", "lobby_opened").await;
+        enqueue(&pool, &subscriber, 1000).await.unwrap();
+
+        deliver_pending(&pool, &subscriber, &client, b"secret", 1000)
+            .await
+            .unwrap();
+        let delivery = sqlx::query!(
+            "SELECT attempts, next_attempt, last_status, delivered_at FROM webhook_delivery"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.next_attempt, 1060);
+        assert_eq!(delivery.last_status, Some(500));
+        assert_eq!(delivery.delivered_at, None);
+
+        // not due yet
+        deliver_pending(&pool, &subscriber, &client, b"secret", 1030)
+            .await
+            .unwrap();
+        assert_eq!(server.requests(), 1);
+
+        deliver_pending(&pool, &subscriber, &client, b"secret", 1060)
+            .await
+            .unwrap();
+        let delivery = sqlx::query!("SELECT attempts, delivered_at FROM webhook_delivery")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.delivered_at, Some(1060));
+
+        let attempts = sqlx::query!("SELECT time, status FROM webhook_attempt ORDER BY attempt_id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let attempts: Vec<(i64, Option<i64>)> =
+            attempts.into_iter().map(|a| (a.time, a.status)).collect();
+        assert_eq!(attempts, [(1000, Some(500)), (1060, Some(204))]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoints_stop_the_batch() {
+        let client = reqwest::Client::new();
+        let pool = memory_pool().await;
+        // nothing listens on the discard port
+        let subscriber = subscriber("http://127.0.0.1:9");
+
+        enqueue(&pool, &subscriber, 1000).await.unwrap();
+        add_event(&pool, "1", "lobby_opened").await;
+        add_event(&pool, "2", "lobby_opened").await;
+        enqueue(&pool, &subscriber, 1000).await.unwrap();
+
+        deliver_pending(&pool, &subscriber, &client, b"secret", 1000)
+            .await
+            .unwrap();
+        let attempts =
+            sqlx::query_scalar!("SELECT attempts FROM webhook_delivery ORDER BY delivery_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(attempts, [1, 0]);
+    }
+
+    #[tokio::test]
+    async fn sends_signed_requests() {
+        let server = FakeServer::start(vec![FakeResponse::new(204, "")]).await;
+        let client = reqwest::Client::new();
+
+        let body = r#"{"kind":"lobby_opened"}"#.to_string();
+        let status = send(
+            &client,
+            &server.url(),
+            b"secret",
+            7,
+            "lobby_opened",
+            body.clone(),
+            1700000000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, reqwest::StatusCode::NO_CONTENT);
+
+        let request = &server.received().await[0];
+        assert_eq!(request.body, body);
+        assert_eq!(request.header("x-webhook-delivery"), Some("7"));
+        assert_eq!(request.header("x-webhook-event"), Some("lobby_opened"));
+        assert_eq!(request.header("x-webhook-timestamp"), Some("1700000000"));
+        let expected = format!(
+            "sha256={}",
+            hmac_hex(b"secret", format!("1700000000.{}", body).as_bytes())
+        );
+        assert_eq!(
+            request.header("x-webhook-signature"),
+            Some(expected.as_str())
+        );
+    }
+}
@@ -1,23 +1,42 @@
+use base64::Engine;
+use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::sqlite::SqlitePool;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::embed::EmbedTemplate;
+use crate::interactions::{self, Interaction, InteractionResponse};
+use crate::lobby::category_name;
+use crate::moderation::{self, Moderation, NewRule, Rule};
+use crate::optout::{self, Choice};
+use crate::settings::WebSettings;
+use crate::steam::{cached_players, Player};
 
 use maud::{html, PreEscaped, DOCTYPE};
-use trillium::{conn_unwrap, Conn, Handler, State};
+use trillium::{conn_unwrap, Body, Conn, Handler, KnownHeaderName, State};
 use trillium_logger::Logger;
 use trillium_router::{Router, RouterConnExt};
 use trillium_static_compiled::static_compiled;
 
 #[tracing::instrument(skip_all)]
-pub async fn run_web_server() -> Result<()> {
-    trillium_tokio::config().run_async(app()).await;
+pub async fn run_web_server(settings: WebSettings, embed: EmbedTemplate) -> Result<()> {
+    trillium_tokio::config()
+        .run_async(app(settings, embed))
+        .await;
     Ok(())
 }
 
-fn app() -> impl Handler {
+fn app(settings: WebSettings, embed: EmbedTemplate) -> impl Handler {
     (
         Logger::new(),
+        State::new(settings),
+        State::new(embed),
         trillium::Init::new(|_| async move {
             let db = SqlitePool::connect(&std::env::var("DATABASE_URL").unwrap())
                 .await
@@ -33,6 +52,18 @@ fn router() -> impl Handler {
     Router::new()
         .get("/", get_servers)
         .get("/server/:time/:lobby_id", get_server)
+        .get("/mod/:mod_id", get_mod)
+        .get("/mods", get_mods)
+        .get("/api/mods", get_mods_api)
+        .get("/api/events", get_events_api)
+        .get("/watch/:lobby_id", get_watch)
+        .get("/admin/moderation", get_moderation)
+        .post("/admin/moderation", post_moderation)
+        .post("/admin/moderation/:rule_id/delete", post_delete_rule)
+        .get("/optout", get_optout)
+        .get("/optout/login", get_optout_login)
+        .get("/optout/verify", get_optout_verify)
+        .post("/interactions", post_interaction)
 }
 
 trait MaudConnExt {
@@ -45,6 +76,18 @@ impl MaudConnExt for Conn {
     }
 }
 
+trait JsonConnExt {
+    fn json<T: Serialize>(self, value: &T) -> Self;
+}
+
+impl JsonConnExt for Conn {
+    fn json<T: Serialize>(mut self, value: &T) -> Self {
+        self.response_headers_mut()
+            .insert(KnownHeaderName::ContentType, "application/json");
+        self.ok(serde_json::to_string(value).unwrap())
+    }
+}
+
 struct Server {
     time: i64,
     time_formatted: String,
@@ -53,6 +96,8 @@ struct Server {
     region: String,
     host_user_id: String,
     server_name: String,
+    modpack: Option<String>,
+    host: Option<Player>,
     mods: Vec<Mod>,
 }
 
@@ -64,6 +109,135 @@ struct Mod {
     url: Option<String>,
 }
 
+struct ModPage {
+    id: i64,
+    name: Option<String>,
+    url: Option<String>,
+    summary: Option<String>,
+    logo: Option<String>,
+    first_seen: Option<String>,
+    last_seen: Option<String>,
+    servers: Vec<Server>,
+    related: Vec<RelatedMod>,
+    modpacks: Vec<String>,
+    history: Vec<(i64, i64)>,
+    versions: Vec<ModVersion>,
+    categories: Vec<ModCategory>,
+    category_events: Vec<ModCategoryEvent>,
+}
+
+struct RelatedMod {
+    id: i64,
+    name: Option<String>,
+    lobbies: i64,
+}
+
+struct ModVersion {
+    version: String,
+    lobbies: i64,
+    first_seen: String,
+    last_seen: String,
+}
+
+struct ModCategoryEvent {
+    time_formatted: String,
+    old_category: i64,
+    new_category: i64,
+}
+
+struct ModCategory {
+    category: i64,
+    lobbies: i64,
+    last_seen: String,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum RankingWindow {
+    #[default]
+    #[serde(rename = "24h")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+}
+
+impl RankingWindow {
+    const ALL: [RankingWindow; 3] = [Self::Day, Self::Week, Self::Month];
+
+    fn seconds(self) -> i64 {
+        match self {
+            Self::Day => 60 * 60 * 24,
+            Self::Week => 60 * 60 * 24 * 7,
+            Self::Month => 60 * 60 * 24 * 30,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Day => "24h",
+            Self::Week => "7d",
+            Self::Month => "30d",
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RankingView {
+    /// Most distinct lobbies within the window
+    #[default]
+    Popular,
+    /// Largest growth in distinct lobbies versus the previous window
+    Trending,
+    /// First seen within the window
+    New,
+}
+
+impl RankingView {
+    const ALL: [RankingView; 3] = [Self::Popular, Self::Trending, Self::New];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Popular => "popular",
+            Self::Trending => "trending",
+            Self::New => "new",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RankingQuery {
+    #[serde(default)]
+    window: RankingWindow,
+    #[serde(default)]
+    view: RankingView,
+}
+
+#[derive(Serialize)]
+struct ModRankings {
+    window: RankingWindow,
+    view: RankingView,
+    mods: Vec<ModRanking>,
+}
+
+#[derive(Serialize)]
+struct ModRanking {
+    id: i64,
+    name: Option<String>,
+    url: Option<String>,
+    lobbies: i64,
+    /// Distinct lobbies in the window before this one (trending view only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_lobbies: Option<i64>,
+    /// Unix time the mod was first seen (new view only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_seen: Option<i64>,
+}
+
+/// Number of hourly buckets shown in the mod usage chart
+const HISTORY_HOURS: i64 = 24 * 7;
+
 async fn get_servers(conn: Conn) -> Conn {
     let pool = conn.state::<SqlitePool>().unwrap();
     let res = sqlx::query!(
@@ -83,8 +257,10 @@ async fn get_servers(conn: Conn) -> Conn {
                     AND server_mod.lobby_id = server.lobby_id
                     AND category != 0
                 ORDER BY category)
-            ) AS "mods?: String"
+            ) AS "mods?: String",
+            server_modpack.modpack AS "modpack?: String"
             FROM server
+            JOIN server_modpack USING(time, lobby_id)
             WHERE diff = 4 AND server.time > strftime('%s', datetime('now', '-1 hours'))
             ORDER BY time;
         "#
@@ -92,7 +268,7 @@ async fn get_servers(conn: Conn) -> Conn {
     .fetch_all(pool)
     .await.unwrap();
 
-    let servers: Vec<Server> = res
+    let mut servers: Vec<Server> = res
         .into_iter()
         .map(|r| Server {
             time: r.time,
@@ -102,12 +278,23 @@ async fn get_servers(conn: Conn) -> Conn {
             region: r.region,
             host_user_id: r.host_user_id,
             server_name: r.server_name,
+            modpack: r.modpack,
+            host: None,
             mods: r
                 .mods
                 .map_or_else(|| Ok(vec![]), |m| serde_json::from_str::<Vec<Mod>>(&m))
                 .unwrap(),
         })
         .collect();
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap();
+    let moderation = Moderation::load(pool, now).await.unwrap();
+    servers.retain(|s| !moderation.hides(&s.host_user_id, &s.lobby_id, &s.server_name));
+    attach_hosts(pool, &mut servers).await.unwrap();
 
     conn.render(render_servers(servers))
 }
@@ -133,8 +320,10 @@ async fn get_server(conn: Conn) -> Conn {
                     AND server_mod.lobby_id = server.lobby_id
                     AND category != 0
                 ORDER BY category)
-            ) AS "mods?: String"
+            ) AS "mods?: String",
+            server_modpack.modpack AS "modpack?: String"
             FROM server
+            JOIN server_modpack USING(time, lobby_id)
             WHERE server.time = ? AND server.lobby_id = ?
             ORDER BY time
         "#,
@@ -144,7 +333,7 @@ async fn get_server(conn: Conn) -> Conn {
     .fetch_all(pool)
     .await.unwrap();
 
-    let servers: Vec<Server> = res
+    let mut servers: Vec<Server> = res
         .into_iter()
         .map(|r| Server {
             time: r.time,
@@ -154,17 +343,732 @@ async fn get_server(conn: Conn) -> Conn {
             region: r.region,
             host_user_id: r.host_user_id,
             server_name: r.server_name,
+            modpack: r.modpack,
+            host: None,
             mods: r
                 .mods
                 .map_or_else(|| Ok(vec![]), |m| serde_json::from_str::<Vec<Mod>>(&m))
                 .unwrap(),
         })
         .collect();
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap();
+    let moderation = Moderation::load(pool, now).await.unwrap();
+    servers.retain(|s| !moderation.hides(&s.host_user_id, &s.lobby_id, &s.server_name));
+    attach_hosts(pool, &mut servers).await.unwrap();
 
-    conn.render(render_servers(servers))
+    conn.render(render_server_page(servers, &lobby_id))
 }
 
-fn render_servers(servers: Vec<Server>) -> PreEscaped<String> {
+async fn get_mod(conn: Conn) -> Conn {
+    let mod_id = conn_unwrap!(
+        conn.param("mod_id").and_then(|id| id.parse::<i64>().ok()),
+        conn
+    );
+
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let m = sqlx::query!(
+        r#"SELECT mod_id,
+            name,
+            url,
+            json_extract(metadata, '$.summary') AS "summary?: String",
+            json_extract(metadata, '$.logo.thumb_320x180') AS "logo?: String"
+            FROM mod
+            WHERE mod_id = ?
+        "#,
+        mod_id,
+    )
+    .fetch_optional(pool)
+    .await
+    .unwrap();
+    let m = conn_unwrap!(m, conn);
+
+    let seen = sqlx::query!(
+        r#"SELECT datetime(MIN(time), 'unixepoch', 'localtime') AS "first_seen?: String",
+            datetime(MAX(time), 'unixepoch', 'localtime') AS "last_seen?: String"
+            FROM server_mod
+            WHERE mod_id = ?
+        "#,
+        mod_id,
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    let res = sqlx::query!(
+        r#"SELECT time,
+            datetime(time, 'unixepoch', 'localtime') AS "time_formatted!: String",
+            lobby_id,
+            diff,
+            region,
+            host_user_id,
+            server_name,
+            (SELECT json_group_array(json_object('id', mod_id, 'category', category, 'name', name, 'url', url)) FROM
+                (SELECT mod_id, category, name, url
+                FROM server_mod
+                JOIN mod USING(mod_id)
+                WHERE
+                    server_mod.time = server.time
+                    AND server_mod.lobby_id = server.lobby_id
+                    AND category != 0
+                ORDER BY category)
+            ) AS "mods?: String",
+            server_modpack.modpack AS "modpack?: String"
+            FROM server
+            JOIN server_modpack USING(time, lobby_id)
+            WHERE server.time = (SELECT MAX(time) FROM server)
+                AND server.lobby_id IN (
+                    SELECT lobby_id
+                    FROM server_mod
+                    WHERE server_mod.time = server.time AND mod_id = ?
+                )
+            ORDER BY diff DESC
+        "#,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    let mut servers: Vec<Server> = res
+        .into_iter()
+        .map(|r| Server {
+            time: r.time,
+            time_formatted: r.time_formatted,
+            lobby_id: r.lobby_id,
+            difficulty: r.diff,
+            region: r.region,
+            host_user_id: r.host_user_id,
+            server_name: r.server_name,
+            modpack: r.modpack,
+            host: None,
+            mods: r
+                .mods
+                .map_or_else(|| Ok(vec![]), |m| serde_json::from_str::<Vec<Mod>>(&m))
+                .unwrap(),
+        })
+        .collect();
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap();
+    let moderation = Moderation::load(pool, now).await.unwrap();
+    servers.retain(|s| !moderation.hides(&s.host_user_id, &s.lobby_id, &s.server_name));
+    attach_hosts(pool, &mut servers).await.unwrap();
+
+    let related = sqlx::query!(
+        r#"SELECT mod_id AS "id!: i64", name, lobbies AS "lobbies!: i64"
+            FROM (
+                SELECT mod_b AS mod_id, lobbies FROM mod_pair WHERE mod_a = ?
+                UNION ALL
+                SELECT mod_a AS mod_id, lobbies FROM mod_pair WHERE mod_b = ?
+            )
+            JOIN mod USING(mod_id)
+            ORDER BY lobbies DESC
+            LIMIT 10
+        "#,
+        mod_id,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| RelatedMod {
+        id: r.id,
+        name: r.name,
+        lobbies: r.lobbies,
+    })
+    .collect();
+
+    let modpacks = sqlx::query!(
+        r#"SELECT name
+            FROM modpack
+            JOIN modpack_mod USING(modpack_id)
+            WHERE mod_id = ?
+            ORDER BY lobbies DESC
+        "#,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| r.name)
+    .collect();
+
+    let history = sqlx::query!(
+        r#"SELECT time / 3600 * 3600 AS "bucket!: i64",
+            COUNT(DISTINCT lobby_id) AS "lobbies!: i64"
+            FROM server_mod
+            WHERE mod_id = ? AND time > strftime('%s', datetime('now', '-7 days'))
+            GROUP BY 1
+            ORDER BY 1
+        "#,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| (r.bucket, r.lobbies))
+    .collect();
+
+    let versions = sqlx::query!(
+        r#"SELECT version AS "version!",
+            COUNT(DISTINCT lobby_id) AS "lobbies!: i64",
+            datetime(MIN(time), 'unixepoch', 'localtime') AS "first_seen!: String",
+            datetime(MAX(time), 'unixepoch', 'localtime') AS "last_seen!: String"
+            FROM server_mod
+            WHERE mod_id = ?
+            GROUP BY version
+            ORDER BY MAX(time) DESC
+        "#,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| ModVersion {
+        version: r.version,
+        lobbies: r.lobbies,
+        first_seen: r.first_seen,
+        last_seen: r.last_seen,
+    })
+    .collect();
+
+    let categories = sqlx::query!(
+        r#"SELECT category AS "category!",
+            COUNT(DISTINCT lobby_id) AS "lobbies!: i64",
+            datetime(MAX(time), 'unixepoch', 'localtime') AS "last_seen!: String"
+            FROM server_mod
+            WHERE mod_id = ?
+            GROUP BY category
+            ORDER BY category
+        "#,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| ModCategory {
+        category: r.category,
+        lobbies: r.lobbies,
+        last_seen: r.last_seen,
+    })
+    .collect();
+
+    let category_events = sqlx::query!(
+        r#"SELECT datetime(time, 'unixepoch', 'localtime') AS "time_formatted!: String",
+            old_category,
+            new_category
+            FROM mod_category_event
+            WHERE mod_id = ?
+            ORDER BY time DESC
+        "#,
+        mod_id,
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| ModCategoryEvent {
+        time_formatted: r.time_formatted,
+        old_category: r.old_category,
+        new_category: r.new_category,
+    })
+    .collect();
+
+    conn.render(render_mod(ModPage {
+        id: m.mod_id,
+        name: m.name,
+        url: m.url,
+        summary: m.summary,
+        logo: m.logo,
+        first_seen: seen.first_seen,
+        last_seen: seen.last_seen,
+        servers,
+        related,
+        modpacks,
+        history,
+        versions,
+        categories,
+        category_events,
+    }))
+}
+
+/// Fills in host names and avatars from the Steam player cache
+async fn attach_hosts(pool: &SqlitePool, servers: &mut [Server]) -> Result<()> {
+    let ids: Vec<&str> = servers.iter().map(|s| s.host_user_id.as_str()).collect();
+    let players = cached_players(pool, &ids).await?;
+    for server in servers {
+        server.host = players.get(&server.host_user_id).cloned();
+    }
+    Ok(())
+}
+
+async fn get_mods(conn: Conn) -> Conn {
+    let Ok(query) = serde_urlencoded::from_str::<RankingQuery>(conn.querystring()) else {
+        return conn.with_status(400).halt();
+    };
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let rankings = query_mod_rankings(pool, query.window, query.view)
+        .await
+        .unwrap();
+    conn.render(render_mods(rankings))
+}
+
+async fn get_mods_api(conn: Conn) -> Conn {
+    let Ok(query) = serde_urlencoded::from_str::<RankingQuery>(conn.querystring()) else {
+        return conn.with_status(400).halt();
+    };
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let rankings = query_mod_rankings(pool, query.window, query.view)
+        .await
+        .unwrap();
+    conn.json(&rankings)
+}
+
+#[derive(Deserialize)]
+struct EventQuery {
+    /// Only events with a greater ID are returned, pass the last ID seen to page through them
+    #[serde(default)]
+    after: i64,
+    lobby_id: Option<String>,
+}
+
+async fn get_events_api(conn: Conn) -> Conn {
+    let Ok(query) = serde_urlencoded::from_str::<EventQuery>(conn.querystring()) else {
+        return conn.with_status(400).halt();
+    };
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let events = crate::event::events_since(pool, query.after, query.lobby_id.as_deref())
+        .await
+        .unwrap();
+    conn.json(&events)
+}
+
+/// Server-Sent Events for players waiting to join a lobby, see `watch.rs`. Reconnecting clients
+/// continue after the `Last-Event-ID` they send. Only recently seen lobbies can be watched.
+async fn get_watch(mut conn: Conn) -> Conn {
+    let lobby_id = conn_unwrap!(conn.param("lobby_id"), conn).to_owned();
+    let pool = conn.state::<SqlitePool>().unwrap().clone();
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap();
+    // no lobby_closed would ever end the stream of an unknown or long gone lobby
+    let recent = crate::watch::is_recent(&pool, &lobby_id, now)
+        .await
+        .unwrap();
+    if !recent {
+        return conn.with_status(404).halt();
+    }
+    let last_event_id = conn
+        .request_headers()
+        .get_str("last-event-id")
+        .and_then(|id| id.parse().ok());
+    let after = match last_event_id {
+        Some(after) => after,
+        None => crate::event::latest_event_id(&pool).await.unwrap(),
+    };
+
+    let (tx, rx) = futures::channel::mpsc::channel(1);
+    tokio::spawn(async move {
+        if let Err(e) = crate::watch::stream_events(pool, lobby_id, after, tx).await {
+            tracing::warn!("event stream failed: {:#}", e);
+        }
+    });
+
+    let headers = conn.response_headers_mut();
+    headers.insert(KnownHeaderName::ContentType, "text/event-stream");
+    headers.insert(KnownHeaderName::CacheControl, "no-cache");
+    let body = rx.map(Ok::<_, std::io::Error>).into_async_read();
+    conn.ok(Body::new_streaming(body, None))
+}
+
+/// Discord slash commands, see `interactions.rs`. Requests not signed with the key in
+/// `DISCORD_PUBLIC_KEY`, or signed more than a few minutes ago, are rejected.
+async fn post_interaction(mut conn: Conn) -> Conn {
+    let key = match std::env::var("DISCORD_PUBLIC_KEY")
+        .map_err(Into::into)
+        .and_then(|k| interactions::parse_public_key(&k))
+    {
+        Ok(key) => key,
+        Err(e) => {
+            tracing::warn!("interactions are disabled: {}", e);
+            return conn.with_status(404).halt();
+        }
+    };
+    let headers = conn.request_headers();
+    let signature = headers
+        .get_str("x-signature-ed25519")
+        .unwrap_or_default()
+        .to_string();
+    let timestamp = headers
+        .get_str("x-signature-timestamp")
+        .unwrap_or_default()
+        .to_string();
+    let Ok(body) = conn.request_body_string().await else {
+        return conn.with_status(400).halt();
+    };
+
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap();
+    if interactions::verify_request(&key, &signature, &timestamp, body.as_bytes(), now).is_err() {
+        return conn
+            .with_status(401)
+            .with_body("invalid request signature")
+            .halt();
+    }
+    let Ok(interaction) = serde_json::from_str::<Interaction>(&body) else {
+        return conn.with_status(400).halt();
+    };
+
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let embed = conn.state::<EmbedTemplate>().unwrap();
+    let response = match interactions::respond(pool, embed, &interaction).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!("failed to answer interaction: {:#}", e);
+            InteractionResponse::ephemeral("Something went wrong")
+        }
+    };
+    conn.json(&response)
+}
+
+/// Status to answer admin requests with unless they carry HTTP basic credentials with the
+/// password in `ADMIN_PASSWORD`, under any user name. Without `ADMIN_PASSWORD` the admin pages do
+/// not exist.
+fn admin_rejection(conn: &Conn) -> Option<u16> {
+    let Ok(password) = std::env::var("ADMIN_PASSWORD") else {
+        return Some(404);
+    };
+    let given = conn
+        .request_headers()
+        .get_str(KnownHeaderName::Authorization)
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|c| base64::engine::general_purpose::STANDARD.decode(c).ok())
+        .and_then(|c| String::from_utf8(c).ok())
+        .and_then(|c| c.split_once(':').map(|(_, p)| p.to_string()));
+    // digests take as long to compare wherever the passwords differ
+    match given {
+        Some(given) if Sha256::digest(&given) == Sha256::digest(&password) => None,
+        _ => Some(401),
+    }
+}
+
+fn reject_admin(mut conn: Conn, status: u16) -> Conn {
+    if status == 401 {
+        conn.response_headers_mut()
+            .insert(KnownHeaderName::WwwAuthenticate, r#"Basic realm="admin""#);
+    }
+    conn.with_status(status).halt()
+}
+
+/// Browsers send credentials along with forms posted from other sites, so changes are only
+/// accepted from pages of this server
+fn is_same_origin(conn: &Conn) -> bool {
+    let headers = conn.request_headers();
+    match (
+        headers.get_str(KnownHeaderName::Origin),
+        headers.get_str(KnownHeaderName::Host),
+    ) {
+        (None, _) => true,
+        (Some(origin), Some(host)) => origin
+            .split_once("://")
+            .is_some_and(|(_, origin_host)| origin_host == host),
+        (Some(_), None) => false,
+    }
+}
+
+fn redirect_to_moderation(mut conn: Conn) -> Conn {
+    conn.response_headers_mut()
+        .insert(KnownHeaderName::Location, "/admin/moderation");
+    conn.with_status(303).halt()
+}
+
+async fn get_moderation(conn: Conn) -> Conn {
+    if let Some(status) = admin_rejection(&conn) {
+        return reject_admin(conn, status);
+    }
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let rules = moderation::list_rules(pool).await.unwrap();
+    conn.render(render_moderation(rules, None))
+}
+
+#[derive(Deserialize)]
+struct RuleForm {
+    kind: String,
+    value: String,
+    action: String,
+    #[serde(default)]
+    reason: String,
+    /// Empty for rules that do not expire
+    #[serde(default)]
+    expire_hours: String,
+}
+
+async fn add_rule_from_form(pool: &SqlitePool, body: &str) -> Result<i64> {
+    let form: RuleForm = serde_urlencoded::from_str(body)?;
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+    let expires_at = match form.expire_hours.trim() {
+        "" => None,
+        hours => {
+            let hours: i64 = hours.parse().context("expiry must be a number of hours")?;
+            if hours < 1 {
+                bail!("expiry must be at least 1 hour");
+            }
+            Some(now + hours * 60 * 60)
+        }
+    };
+    let rule = NewRule {
+        kind: form.kind.parse()?,
+        value: form.value.trim(),
+        action: form.action.parse()?,
+        reason: form.reason.trim(),
+        expires_at,
+    };
+    moderation::add_rule(pool, &rule, now).await
+}
+
+async fn post_moderation(mut conn: Conn) -> Conn {
+    if let Some(status) = admin_rejection(&conn) {
+        return reject_admin(conn, status);
+    }
+    if !is_same_origin(&conn) {
+        return conn.with_status(403).halt();
+    }
+    let Ok(body) = conn.request_body_string().await else {
+        return conn.with_status(400).halt();
+    };
+
+    let pool = conn.state::<SqlitePool>().unwrap();
+    match add_rule_from_form(pool, &body).await {
+        Ok(rule_id) => {
+            tracing::info!("added moderation rule {}", rule_id);
+            redirect_to_moderation(conn)
+        }
+        Err(e) => {
+            let rules = moderation::list_rules(pool).await.unwrap();
+            conn.render(render_moderation(rules, Some(format!("{:#}", e))))
+                .with_status(400)
+        }
+    }
+}
+
+async fn post_delete_rule(conn: Conn) -> Conn {
+    if let Some(status) = admin_rejection(&conn) {
+        return reject_admin(conn, status);
+    }
+    if !is_same_origin(&conn) {
+        return conn.with_status(403).halt();
+    }
+    let rule_id = conn_unwrap!(conn.param("rule_id").and_then(|id| id.parse().ok()), conn);
+
+    let pool = conn.state::<SqlitePool>().unwrap();
+    if moderation::remove_rule(pool, rule_id).await.unwrap() {
+        tracing::info!("removed moderation rule {}", rule_id);
+    }
+    redirect_to_moderation(conn)
+}
+
+/// Hosts opting out sign in with Steam, see `optout.rs`. Without `public_url` in the `[web]`
+/// settings the pages do not exist.
+async fn get_optout(conn: Conn) -> Conn {
+    let settings = conn.state::<WebSettings>().unwrap();
+    if settings.public_url.is_none() {
+        return conn.with_status(404).halt();
+    }
+    conn.render(render_optout(None))
+}
+
+#[derive(Deserialize)]
+struct OptOutQuery {
+    choice: String,
+}
+
+async fn get_optout_login(mut conn: Conn) -> Conn {
+    let settings = conn.state::<WebSettings>().unwrap();
+    let Some(public_url) = &settings.public_url else {
+        return conn.with_status(404).halt();
+    };
+    let Some(choice) = serde_urlencoded::from_str::<OptOutQuery>(conn.querystring())
+        .ok()
+        .and_then(|q| Choice::parse(&q.choice))
+    else {
+        return conn.with_status(400).halt();
+    };
+
+    let url = optout::login_url(&settings.steam_openid_endpoint, public_url, choice).unwrap();
+    conn.response_headers_mut()
+        .insert(KnownHeaderName::Location, url);
+    conn.with_status(303).halt()
+}
+
+async fn verify_optout(conn: &Conn, settings: &WebSettings, public_url: &str) -> Result<Choice> {
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(conn.querystring())?;
+    let client = reqwest::Client::new();
+    let (host_user_id, choice) = optout::verify(
+        &client,
+        &settings.steam_openid_endpoint,
+        public_url,
+        &params,
+    )
+    .await?;
+
+    let pool = conn.state::<SqlitePool>().unwrap();
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+    optout::set_choice(pool, &host_user_id, choice, now).await?;
+    tracing::info!("host {} opted {}", host_user_id, choice.as_str());
+    Ok(choice)
+}
+
+async fn get_optout_verify(conn: Conn) -> Conn {
+    let settings = conn.state::<WebSettings>().unwrap().clone();
+    let Some(public_url) = &settings.public_url else {
+        return conn.with_status(404).halt();
+    };
+
+    match verify_optout(&conn, &settings, public_url).await {
+        Ok(choice) => conn.render(render_optout(Some(Ok(choice)))),
+        Err(e) => {
+            tracing::warn!("failed to verify opt-out: {:#}", e);
+            conn.render(render_optout(Some(Err(format!("{:#}", e)))))
+                .with_status(400)
+        }
+    }
+}
+
+async fn query_mod_rankings(
+    pool: &SqlitePool,
+    window: RankingWindow,
+    view: RankingView,
+) -> Result<ModRankings> {
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .try_into()?;
+    let since = now - window.seconds();
+    let previous_since = since - window.seconds();
+
+    let mods = match view {
+        RankingView::Popular => sqlx::query!(
+            r#"SELECT mod_id AS "id!: i64",
+                name,
+                url,
+                COUNT(DISTINCT lobby_id) AS "lobbies!: i64"
+                FROM server_mod
+                JOIN mod USING(mod_id)
+                WHERE time > ?
+                GROUP BY mod_id
+                ORDER BY 4 DESC
+                LIMIT 100
+            "#,
+            since,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| ModRanking {
+            id: r.id,
+            name: r.name,
+            url: r.url,
+            lobbies: r.lobbies,
+            previous_lobbies: None,
+            first_seen: None,
+        })
+        .collect(),
+        RankingView::Trending => sqlx::query!(
+            r#"SELECT id AS "id!: i64",
+                name,
+                url,
+                lobbies AS "lobbies!: i64",
+                previous_lobbies AS "previous_lobbies!: i64"
+                FROM (
+                    SELECT mod_id AS id,
+                    name,
+                    url,
+                    COUNT(DISTINCT CASE WHEN time > ? THEN lobby_id END) AS lobbies,
+                    COUNT(DISTINCT CASE WHEN time <= ? THEN lobby_id END) AS previous_lobbies
+                    FROM server_mod
+                    JOIN mod USING(mod_id)
+                    WHERE time > ?
+                    GROUP BY mod_id
+                )
+                WHERE lobbies > previous_lobbies
+                ORDER BY (lobbies - previous_lobbies) * 1.0 / (previous_lobbies + 1) DESC, lobbies DESC
+                LIMIT 100
+            "#,
+            since,
+            since,
+            previous_since,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| ModRanking {
+            id: r.id,
+            name: r.name,
+            url: r.url,
+            lobbies: r.lobbies,
+            previous_lobbies: Some(r.previous_lobbies),
+            first_seen: None,
+        })
+        .collect(),
+        RankingView::New => sqlx::query!(
+            r#"SELECT mod_id AS "id!: i64",
+                name,
+                url,
+                COUNT(DISTINCT lobby_id) AS "lobbies!: i64",
+                MIN(time) AS "first_seen!: i64"
+                FROM server_mod
+                JOIN mod USING(mod_id)
+                GROUP BY mod_id
+                HAVING MIN(time) > ?
+                ORDER BY 5 DESC
+                LIMIT 100
+            "#,
+            since,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| ModRanking {
+            id: r.id,
+            name: r.name,
+            url: r.url,
+            lobbies: r.lobbies,
+            previous_lobbies: None,
+            first_seen: Some(r.first_seen),
+        })
+        .collect(),
+    };
+
+    Ok(ModRankings { window, view, mods })
+}
+
+fn render_page(content: PreEscaped<String>) -> PreEscaped<String> {
     html! {
         html lang="en" {
             (DOCTYPE)
@@ -174,7 +1078,7 @@ fn render_servers(servers: Vec<Server>) -> PreEscaped<String> {
                 link href="/static/css/bootstrap.min.css" rel="stylesheet";
                 style {
                     (PreEscaped(r#"
-                        body > ul {
+                        body > nav, body > ul, body > main {
                             max-width: 700px;
                             width: auto;
                             margin: 0 auto;
@@ -183,13 +1087,440 @@ fn render_servers(servers: Vec<Server>) -> PreEscaped<String> {
                 }
             }
             body {
+                nav.nav."my-2" {
+                    a.nav-link href="/" { "Lobbies" }
+                    a.nav-link href="/mods" { "Mods" }
+                }
+                (content)
+            }
+        }
+    }
+}
+
+fn render_servers(servers: Vec<Server>) -> PreEscaped<String> {
+    render_page(html! {
+        ul.list-group {
+            @for server in servers {
+                (render_server(server))
+            }
+        }
+    })
+}
+
+fn render_server_page(servers: Vec<Server>, lobby_id: &str) -> PreEscaped<String> {
+    render_page(html! {
+        ul.list-group {
+            @for server in servers {
+                (render_server(server))
+            }
+        }
+        main."my-3" {
+            button #watch.btn.btn-outline-primary.btn-sm type="button" data-lobby-id=(lobby_id) {
+                "Notify me when a slot opens"
+            }
+            span #watch-status."ms-2"."opacity-75" {}
+            script {
+                (PreEscaped(r#"
+                    const button = document.getElementById("watch");
+                    const status = document.getElementById("watch-status");
+                    const notify = (text) => {
+                        status.textContent = text;
+                        if ("Notification" in window && Notification.permission === "granted") {
+                            new Notification(text);
+                        }
+                    };
+                    button.addEventListener("click", async () => {
+                        if ("Notification" in window && Notification.permission === "default") {
+                            await Notification.requestPermission();
+                        }
+                        button.disabled = true;
+                        status.textContent = "Watching, keep this page open";
+                        const source = new EventSource("/watch/" + encodeURIComponent(button.dataset.lobbyId));
+                        source.addEventListener("slot_opened", () => notify("A slot opened"));
+                        source.addEventListener("returned_to_rig", () => notify("The lobby returned to the Space Rig"));
+                        source.addEventListener("lobby_closed", () => {
+                            source.close();
+                            button.disabled = false;
+                            notify("The lobby closed");
+                        });
+                        source.addEventListener("expired", () => {
+                            source.close();
+                            button.disabled = false;
+                            status.textContent = "Stopped watching after 6 hours";
+                        });
+                        source.addEventListener("error", () => {
+                            // lobbies that are gone cannot be watched
+                            if (source.readyState === EventSource.CLOSED) {
+                                button.disabled = false;
+                                status.textContent = "This lobby cannot be watched anymore";
+                            }
+                        });
+                    });
+                "#))
+            }
+        }
+    })
+}
+
+fn render_mod(m: ModPage) -> PreEscaped<String> {
+    render_page(html! {
+        main {
+            div.d-flex."gap-3"."my-3" {
+                @if let Some(logo) = &m.logo {
+                    img.rounded src=(logo) width="160" alt="";
+                }
+                div {
+                    h4 {
+                        @if let Some(name) = &m.name {
+                            (name)
+                        } @else {
+                            "Hidden mod ("(m.id)")"
+                        }
+                    }
+                    @if let Some(summary) = &m.summary {
+                        p."mb-1" { (summary) }
+                    }
+                    @if let Some(url) = &m.url {
+                        a href=(url) { "View on mod.io" }
+                    }
+                }
+            }
+            dl.row {
+                dt."col-sm-3" { "First seen" }
+                dd."col-sm-9" { (m.first_seen.as_deref().unwrap_or("Never")) }
+                dt."col-sm-3" { "Last seen" }
+                dd."col-sm-9" { (m.last_seen.as_deref().unwrap_or("Never")) }
+            }
+            @if !m.related.is_empty() {
+                h5 { "Often used with" }
+                ul {
+                    @for r in &m.related {
+                        li {
+                            a href=(format!("/mod/{}", r.id)) {
+                                @if let Some(name) = &r.name {
+                                    (name)
+                                } @else {
+                                    "Hidden mod ("(r.id)")"
+                                }
+                            }
+                            span."opacity-50" { " - " (r.lobbies) " lobbies" }
+                        }
+                    }
+                }
+            }
+            @if !m.modpacks.is_empty() {
+                h5 { "Part of modpacks" }
+                p {
+                    @for name in &m.modpacks {
+                        span.badge.text-bg-secondary."me-1" { (name) }
+                    }
+                }
+            }
+            h5 { "Lobbies over the last 7 days" }
+            (render_history_chart(&m.history))
+            h5."mt-3" { "Versions seen" }
+            table.table.table-sm {
+                thead {
+                    tr {
+                        th { "Version" }
+                        th { "Lobbies" }
+                        th { "First seen" }
+                        th { "Last seen" }
+                    }
+                }
+                tbody {
+                    @for v in &m.versions {
+                        tr {
+                            td { (v.version) }
+                            td { (v.lobbies) }
+                            td { (v.first_seen) }
+                            td { (v.last_seen) }
+                        }
+                    }
+                }
+            }
+            h5 { "Categories seen" }
+            table.table.table-sm {
+                thead {
+                    tr {
+                        th { "Category" }
+                        th { "Lobbies" }
+                        th { "Last seen" }
+                    }
+                }
+                tbody {
+                    @for c in &m.categories {
+                        tr {
+                            td { (category_name(c.category)) }
+                            td { (c.lobbies) }
+                            td { (c.last_seen) }
+                        }
+                    }
+                }
+            }
+            @if !m.category_events.is_empty() {
+                h5 { "Category history" }
+                ul {
+                    @for e in &m.category_events {
+                        li {
+                            (category_name(e.old_category))
+                            " → "
+                            (category_name(e.new_category))
+                            span."opacity-50" { " - " (e.time_formatted) }
+                        }
+                    }
+                }
+            }
+            h5 { "Current lobbies" }
+            @if m.servers.is_empty() {
+                p."opacity-75" { "Not currently running in any lobby." }
+            } @else {
                 ul.list-group {
-                    @for server in servers {
+                    @for server in m.servers {
                         (render_server(server))
                     }
                 }
             }
         }
+    })
+}
+
+fn render_mods(rankings: ModRankings) -> PreEscaped<String> {
+    let link = |window: RankingWindow, view: RankingView| {
+        format!("/mods?window={}&view={}", window.as_str(), view.as_str())
+    };
+    render_page(html! {
+        main {
+            ul.nav.nav-tabs."mb-2" {
+                @for view in RankingView::ALL {
+                    li.nav-item {
+                        a.nav-link.active[view == rankings.view] href=(link(rankings.window, view)) {
+                            (match view {
+                                RankingView::Popular => "Popular",
+                                RankingView::Trending => "Trending",
+                                RankingView::New => "New",
+                            })
+                        }
+                    }
+                }
+            }
+            ul.nav.nav-pills."mb-3" {
+                @for window in RankingWindow::ALL {
+                    li.nav-item {
+                        a.nav-link.active[window == rankings.window] href=(link(window, rankings.view)) {
+                            (window.as_str())
+                        }
+                    }
+                }
+            }
+            table.table.table-sm {
+                thead {
+                    tr {
+                        th { "#" }
+                        th { "Mod" }
+                        th { "Lobbies" }
+                        @if rankings.view == RankingView::Trending {
+                            th { "Previous" }
+                        }
+                        @if rankings.view == RankingView::New {
+                            th { "First seen" }
+                        }
+                    }
+                }
+                tbody {
+                    @for (i, m) in rankings.mods.into_iter().enumerate() {
+                        @let rank = i + 1;
+                        tr {
+                            td { (rank) }
+                            td {
+                                a href=(format!("/mod/{}", m.id)) {
+                                    @if let Some(name) = m.name {
+                                        (name)
+                                    } @else {
+                                        "Hidden mod ("(m.id)")"
+                                    }
+                                }
+                            }
+                            td { (m.lobbies) }
+                            @if let Some(previous) = m.previous_lobbies {
+                                td { (previous) }
+                            }
+                            @if let Some(first_seen) = m.first_seen {
+                                td.text-nowrap { (format_age(first_seen)) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn render_moderation(rules: Vec<Rule>, error: Option<String>) -> PreEscaped<String> {
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    render_page(html! {
+        main {
+            h4."my-3" { "Moderation" }
+            p."opacity-75" {
+                "Block rules hide matching lobbies from these pages and Discord. Allow rules keep "
+                "matching lobbies visible even if a block rule matches them too."
+            }
+            @if let Some(error) = error {
+                div.alert.alert-danger { (error) }
+            }
+            form.row."g-2"."mb-4" method="post" action="/admin/moderation" {
+                div."col-3" {
+                    select.form-select name="action" {
+                        option value="block" { "Block" }
+                        option value="allow" { "Allow" }
+                    }
+                }
+                div."col-3" {
+                    select.form-select name="kind" {
+                        option value="name" { "Name pattern" }
+                        option value="host" { "Host Steam ID" }
+                        option value="lobby" { "Lobby ID" }
+                    }
+                }
+                div."col-6" {
+                    input.form-control name="value" placeholder="Value" required;
+                }
+                div."col-6" {
+                    input.form-control name="reason" placeholder="Reason";
+                }
+                div."col-3" {
+                    input.form-control name="expire_hours" type="number" min="1" placeholder="Hours";
+                }
+                div."col-3" {
+                    button.btn.btn-primary."w-100" type="submit" { "Add rule" }
+                }
+            }
+            table.table.table-sm {
+                thead {
+                    tr {
+                        th { "#" }
+                        th { "Rule" }
+                        th { "Reason" }
+                        th { "Expires" }
+                        th {}
+                    }
+                }
+                tbody {
+                    @for rule in rules {
+                        tr."opacity-50"[rule.expires_at.is_some_and(|e| e <= now)] {
+                            td { (rule.rule_id) }
+                            td {
+                                (rule.action.as_str())
+                                " "
+                                (rule.kind.as_str())
+                                " "
+                                code { (rule.value) }
+                            }
+                            td { (rule.reason) }
+                            td.text-nowrap {
+                                (format_expiry(rule.expires_at, now))
+                            }
+                            td {
+                                form method="post" action=(format!("/admin/moderation/{}/delete", rule.rule_id)) {
+                                    button.btn.btn-sm.btn-outline-danger type="submit" { "Remove" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// The opt-out page, with the outcome of signing in if the host just did
+fn render_optout(outcome: Option<Result<Choice, String>>) -> PreEscaped<String> {
+    render_page(html! {
+        main {
+            h4."my-3" { "Opt out" }
+            @match outcome {
+                Some(Ok(Choice::OptOut)) => {
+                    div.alert.alert-success {
+                        "Your lobbies are no longer listed here, in the API or on Discord."
+                    }
+                }
+                Some(Ok(Choice::OptIn)) => {
+                    div.alert.alert-success { "Your lobbies are listed again." }
+                }
+                Some(Err(error)) => {
+                    div.alert.alert-danger { "Signing in failed: " (error) }
+                }
+                None => {}
+            }
+            p {
+                "Hosts can stop their lobbies and Steam profiles from being listed on this site, "
+                "its API and the Discord channels it posts to. Sign in through Steam to prove the "
+                "account is yours, this site only learns your Steam ID."
+            }
+            div.d-flex."gap-2" {
+                a.btn.btn-primary href="/optout/login?choice=out" { "Sign in through Steam and opt out" }
+                a.btn.btn-outline-secondary href="/optout/login?choice=in" { "Sign in and opt back in" }
+            }
+        }
+    })
+}
+
+fn format_expiry(expires_at: Option<i64>, now: i64) -> String {
+    match expires_at {
+        Some(expires_at) if expires_at <= now => "expired".to_string(),
+        Some(expires_at) => format!("in {}h", (expires_at - now + 3599) / 3600),
+        None => "never".to_string(),
+    }
+}
+
+fn render_history_chart(history: &[(i64, i64)]) -> PreEscaped<String> {
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap();
+    let end = now / 3600 * 3600;
+    let start = end - (HISTORY_HOURS - 1) * 3600;
+
+    let counts: HashMap<i64, i64> = history.iter().copied().collect();
+    let peak = counts.values().copied().max().unwrap_or(0);
+
+    let (width, height) = (700, 120);
+    let points = (0..HISTORY_HOURS)
+        .map(|i| {
+            let count = counts.get(&(start + i * 3600)).copied().unwrap_or(0);
+            let x = i * width / (HISTORY_HOURS - 1);
+            let y = height - count * height / peak.max(1);
+            format!("{x},{y}")
+        })
+        .join(" ");
+
+    html! {
+        svg."w-100".border.rounded viewBox=(format!("0 0 {width} {height}")) preserveAspectRatio="none" height=(height) {
+            polyline points=(points) fill="none" stroke="currentColor" stroke-width="2" vector-effect="non-scaling-stroke";
+        }
+        div.d-flex.justify-content-between {
+            small."opacity-50" { "7 days ago" }
+            small."opacity-50" { "Peak: " (peak) " lobbies/hour" }
+            small."opacity-50" { "Now" }
+        }
+    }
+}
+
+fn format_age(time: i64) -> String {
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+        - time;
+    match age {
+        ..=3599 => format!("{} minutes ago", age / 60),
+        3600..=86399 => format!("{} hours ago", age / 3600),
+        _ => format!("{} days ago", age / 86400),
     }
 }
 
@@ -204,10 +1535,19 @@ fn render_server(server: Server) -> PreEscaped<String> {
                         a href=(format!("steam://joinlobby/548430/{}/{}", server.lobby_id, server.host_user_id)) {
                             (server.server_name)
                         }
+                        @if let Some(modpack) = &server.modpack {
+                            " "
+                            span.badge.text-bg-secondary { (modpack) }
+                        }
                     }
                     p."mb-0"."opacity-75" {
                         a href=(format!("https://steamcommunity.com/profiles/{}", server.host_user_id)) {
-                            "Steam profile"
+                            @if let Some(host) = &server.host {
+                                img.rounded."me-1" src=(host.avatar) width="16" height="16" alt="";
+                                (host.personaname)
+                            } @else {
+                                "Steam profile"
+                            }
                         }
                         " - "
                         a href=(format!("/server/{}/{}", server.time, server.lobby_id)) {
@@ -217,19 +1557,14 @@ fn render_server(server: Server) -> PreEscaped<String> {
                             @for m in server.mods {
                                 @if let Some(category) = m.category {
                                     li {
-                                        (match category {
-                                            0 => "Verified",
-                                            1 => "Approved",
-                                            2 => "Sandbox",
-                                            _ => "Unknown"
-                                        })
+                                        (category_name(category.into()))
                                         " - "
-                                        @if let (Some(url), Some(name)) = (m.url, m.name) {
-                                            a href=(url) {
+                                        a href=(format!("/mod/{}", m.id)) {
+                                            @if let Some(name) = m.name {
                                                 (name)
+                                            } @else {
+                                                "Hidden mod ("(m.id)")"
                                             }
-                                        } @else {
-                                            "Hidden mod ("(m.id)")"
                                         }
                                     }
                                 }
@@ -244,3 +1579,35 @@ fn render_server(server: Server) -> PreEscaped<String> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranking(id: i64, name: Option<&str>, lobbies: i64) -> ModRanking {
+        ModRanking {
+            id,
+            name: name.map(str::to_string),
+            url: None,
+            lobbies,
+            previous_lobbies: None,
+            first_seen: None,
+        }
+    }
+
+    #[test]
+    fn ranks_mods_in_order() {
+        let page = render_mods(ModRankings {
+            window: RankingWindow::Week,
+            view: RankingView::Popular,
+            mods: vec![ranking(1, Some("Better Spawns"), 12), ranking(2, None, 3)],
+        })
+        .into_string();
+        let first = page.find("<td>1</td>").unwrap();
+        let second = page.find("<td>2</td>").unwrap();
+        assert!(first < second);
+        assert!(page.contains("Better Spawns"));
+        assert!(page.contains("Hidden mod (2)"));
+        assert!(page.contains(r#"href="/mods?window=7d&amp;view=trending""#));
+    }
+}